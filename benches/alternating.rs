@@ -1,7 +1,8 @@
-use beetree::Map;
+use beetree::{InsertStrategy, Map};
 
-fn run_lazy(n: usize) -> Map<u64, usize> {
+fn run_lazy(n: usize, strategy: InsertStrategy) -> Map<u64, usize> {
     let mut map = Map::new();
+    map.set_insert_strategy(strategy);
     let mut previous_idx: Option<u64> = None;
     for i in 0..n {
         if let Some(idx) = previous_idx {
@@ -22,7 +23,12 @@ fn criterion_benchmark(c: &mut Criterion) {
     for i in [100, 10_000].iter() {
         group.bench_with_input(BenchmarkId::new("LazyMap", i), i, |b, i| {
             b.iter(|| {
-                run_lazy(black_box(*i)).get(&2);
+                run_lazy(black_box(*i), InsertStrategy::Lazy).get(&2);
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("LazyMap-Adaptive", i), i, |b, i| {
+            b.iter(|| {
+                run_lazy(black_box(*i), InsertStrategy::Adaptive).get(&2);
             })
         });
     }
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+use beetree::{BPlusMap, Map};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+/// Random keys, and the order in which to look them up.
+fn keys(n: usize) -> (Vec<u64>, Vec<u64>) {
+    let mut rng = StdRng::seed_from_u64(n as u64);
+    let keys: Vec<u64> = (0..n).map(|_| rng.random()).collect();
+    let lookups = (0..1000).map(|_| keys[rng.random_range(0..n)]).collect();
+    (keys, lookups)
+}
+
+fn build_lazy(keys: &[u64]) -> Map<u64, usize> {
+    let mut map = Map::new();
+    for (i, &key) in keys.iter().enumerate() {
+        map.insert(key, i);
+    }
+    map.flush();
+    map
+}
+
+fn build_bplus(keys: &[u64]) -> BPlusMap<u64, usize> {
+    let mut map = BPlusMap::new();
+    for (i, &key) in keys.iter().enumerate() {
+        map.insert(key, i);
+    }
+    map.flush();
+    map
+}
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use std::hint::black_box;
+
+/// Lookups and short range scans in flushed maps. The B+-tree reads a
+/// leaf for every separator it compares against on the way down, where
+/// `Map` and `BTreeMap` find the keys in the node.
+fn criterion_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("BPlus Lookup");
+    for i in [10_000, 1_000_000].iter() {
+        let (keys, lookups) = keys(*i);
+        let lazy = build_lazy(&keys);
+        let bplus = build_bplus(&keys);
+        let btree: BTreeMap<_, _> = keys.iter().enumerate().map(|(i, &k)| (k, i)).collect();
+        group.bench_with_input(BenchmarkId::new("LazyMap", i), &lookups, |b, lookups| {
+            b.iter(|| {
+                for key in lookups {
+                    black_box(lazy.get(black_box(key)));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("BPlusMap", i), &lookups, |b, lookups| {
+            b.iter(|| {
+                for key in lookups {
+                    black_box(bplus.get(black_box(key)));
+                }
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("BTreeMap", i), &lookups, |b, lookups| {
+            b.iter(|| {
+                for key in lookups {
+                    black_box(btree.get(black_box(key)));
+                }
+            })
+        });
+        group.bench_with_input(
+            BenchmarkId::new("BPlusMap-Range", i),
+            &lookups,
+            |b, lookups| {
+                b.iter(|| {
+                    for key in lookups {
+                        black_box(bplus.range(black_box(key)..).take(100).count());
+                    }
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("BTreeMap-Range", i),
+            &lookups,
+            |b, lookups| {
+                b.iter(|| {
+                    for key in lookups {
+                        black_box(btree.range(black_box(key)..).take(100).count());
+                    }
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
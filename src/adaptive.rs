@@ -0,0 +1,171 @@
+use crate::{Branch, Map, Motion, Visitor};
+
+/// How far the read/write balance is tracked in either direction.
+/// A map that has just seen a long burst of writes switches back
+/// to eager insertion once it has seen one more read than this.
+const WINDOW: i32 = 16;
+
+/// Controls where [`Map::insert`] puts new items.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InsertStrategy {
+    /// Always buffer new items at the root, to be pushed down by later queries.
+    #[default]
+    Lazy,
+    /// Track the recent mix of queries and inserts. While queries are at least
+    /// as frequent as inserts, new items are carried straight down to their
+    /// leaf. During bursts of inserts they are buffered, as with
+    /// [`Lazy`](InsertStrategy::Lazy).
+    Adaptive,
+}
+
+/// Carries a single item down to the leaf it belongs in, or to the
+/// separator with its key, pushing down the buffers on the way so that it
+/// doesn't overtake older items.
+pub(crate) struct InsertVisitor<K, V> {
+    item: Option<(K, V)>,
+}
+
+impl<K, V> InsertVisitor<K, V> {
+    pub(crate) fn new(key: K, value: V) -> Self {
+        InsertVisitor {
+            item: Some((key, value)),
+        }
+    }
+}
+
+impl<K: Ord, V> Visitor<K, V> for InsertVisitor<K, V> {
+    #[inline]
+    fn visit_internal(&mut self, array: &mut [Branch<K, V>], _temporary: bool) -> Motion {
+        let Some((key, _)) = &self.item else {
+            return Motion::Finish;
+        };
+        match array.binary_search_by(|b| b.key.cmp(key)) {
+            Ok(i) => Motion::Replace(i),
+            Err(i) => Motion::VisitChild(i),
+        }
+    }
+
+    #[inline]
+    fn visit_leaf(&mut self, _array: &mut [(K, V)]) {}
+
+    #[inline]
+    fn deliver(&mut self) -> Option<(K, V)> {
+        self.item.take()
+    }
+}
+
+impl<K, V> Map<K, V> {
+    /// The strategy [`Map::insert`] currently follows. New maps start out
+    /// [`Lazy`](InsertStrategy::Lazy).
+    pub fn insert_strategy(&self) -> InsertStrategy {
+        self.insert_strategy
+    }
+
+    /// Changes where [`Map::insert`] puts new items, starting with the next
+    /// insert. Items that are already buffered stay where they are until a
+    /// query or flush pushes them down.
+    ///
+    /// The mix of queries and inserts is tracked under either strategy, so
+    /// switching to [`Adaptive`](InsertStrategy::Adaptive) acts on the
+    /// map's recent history right away.
+    pub fn set_insert_strategy(&mut self, strategy: InsertStrategy) {
+        self.insert_strategy = strategy;
+    }
+
+    pub(crate) fn record_read(&self) {
+        self.read_bias.set((self.read_bias.get() + 1).min(WINDOW));
+    }
+
+    /// Records an insert, and returns whether it should be carried
+    /// straight down to its leaf.
+    pub(crate) fn record_write(&mut self) -> bool {
+        let bias = self.read_bias.get_mut();
+        let eager = self.insert_strategy == InsertStrategy::Adaptive && *bias > 0;
+        *bias = (*bias - 1).max(-WINDOW);
+        eager
+    }
+
+    pub(crate) fn record_burst(&mut self) {
+        *self.read_bias.get_mut() = -WINDOW;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::WINDOW;
+    use crate::{B, InsertStrategy, Map};
+
+    #[test]
+    fn switch_after_window_reads() {
+        for reads in [WINDOW, WINDOW + 1] {
+            let mut map = Map::new();
+            map.set_insert_strategy(InsertStrategy::Adaptive);
+            map.extend_from_vec(&mut (0..B * 4).map(|i| (i * 2, i)).collect());
+            map.flush();
+            for _ in 0..reads {
+                map.get(&0);
+            }
+            map.insert(1, 0);
+            let eager = map.stats().buffered == 0;
+            assert_eq!(eager, reads > WINDOW);
+        }
+    }
+
+    #[test]
+    fn adaptive_alternate() {
+        let mut map = Map::new();
+        map.set_insert_strategy(InsertStrategy::Adaptive);
+
+        for i in 0..B * B * 3 {
+            map.insert(i, i);
+            assert_eq!(map.get(&i), Some(&i));
+        }
+        for i in 0..B * B * 3 {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+    }
+
+    #[test]
+    fn adaptive_replaces_older_buffered_values() {
+        let mut map = Map::new();
+        map.set_insert_strategy(InsertStrategy::Adaptive);
+        let max = B * B * 3;
+        for i in 0..max {
+            map.insert(i, Rc::new(i));
+        }
+
+        // The burst above was buffered, so the eager inserts below have to
+        // push the older values out of their way.
+        let x = Rc::new(0);
+        for _ in 0..WINDOW * 2 {
+            map.get(&0);
+        }
+        for i in (0..max).step_by(B) {
+            map.insert(i, x.clone());
+            assert!(Rc::ptr_eq(map.get(&i).unwrap(), &x));
+        }
+        map.flush();
+        assert_eq!(Rc::strong_count(&x), max.div_ceil(B) + 1);
+    }
+
+    #[test]
+    fn adaptive_buffers_bursts() {
+        let mut map = Map::new();
+        map.set_insert_strategy(InsertStrategy::Adaptive);
+        map.insert(0, Rc::new(0));
+        map.get(&0);
+
+        let x = Rc::new(0);
+        map.insert(1, x.clone());
+        for _ in 0..10 {
+            map.insert(2, x.clone());
+        }
+        // The first insert after a read went straight to its leaf,
+        // the rest were buffered.
+        assert_eq!(Rc::strong_count(&x), 12);
+        assert_eq!(map.get(&2), Some(&x));
+        assert_eq!(Rc::strong_count(&x), 3);
+    }
+}
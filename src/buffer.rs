@@ -0,0 +1,446 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, VecDeque, binary_heap::PeekMut},
+    mem::take,
+};
+
+use crate::vec_slicer::SliceThief;
+
+/// Past this many runs, the buffer stops keeping track of them and treats
+/// its items as unsorted.
+const MAX_RUNS: usize = 64;
+
+/// The items waiting to be pushed out of a node, in insertion order.
+///
+/// The items are kept as a sequence of sorted runs, like the runs of
+/// timsort. When the buffer is processed the runs are merged, which only
+/// takes linear time for a single run and `O(n log k)` for `k` runs.
+/// The new order is worked out on indices before any item moves, so a
+/// panicking `Ord` leaves the buffer as it was.
+#[derive(Clone)]
+pub(crate) struct Buffer<K, V> {
+    items: VecDeque<(K, V)>,
+    /// Lengths of the sorted runs that make up `items`, front to back.
+    /// Empty when `items` is, or when there were too many runs to track.
+    runs: Vec<usize>,
+}
+
+impl<K, V> Default for Buffer<K, V> {
+    fn default() -> Self {
+        Buffer {
+            items: VecDeque::new(),
+            runs: Vec::new(),
+        }
+    }
+}
+
+impl<K, V> Buffer<K, V> {
+    /// A buffer of `items` in insertion order. Nothing is compared, so
+    /// the items are treated as unsorted.
+    pub(crate) fn from_unsorted(items: Vec<(K, V)>) -> Self {
+        Buffer {
+            items: VecDeque::from(items),
+            runs: Vec::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// The items in insertion order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &(K, V)> {
+        self.items.iter()
+    }
+
+    /// Whether the items are already in key order, so that processing the
+    /// buffer won't need to merge or sort them.
+    pub(crate) fn is_sorted(&self) -> bool {
+        self.runs.len() <= 1 && !self.is_scattered()
+    }
+
+    fn is_scattered(&self) -> bool {
+        self.runs.is_empty() && !self.items.is_empty()
+    }
+
+    fn scatter(&mut self) {
+        self.runs.clear();
+    }
+
+    /// Gives a vector returned by [`take_sorted`](Buffer::take_sorted) back to
+    /// the buffer, so that its allocation can be reused.
+    pub(crate) fn recycle(&mut self, vec: Vec<(K, V)>) {
+        debug_assert!(vec.is_empty());
+        if self.items.is_empty() && self.items.capacity() < vec.capacity() {
+            self.items = VecDeque::from(vec);
+        }
+    }
+}
+
+impl<K: Ord, V> Buffer<K, V> {
+    pub(crate) fn push(&mut self, key: K, value: V) {
+        if self.is_scattered() {
+            self.items.push_back((key, value));
+            return;
+        }
+        match (self.items.front(), self.items.back()) {
+            (None, _) | (_, None) => self.runs.push(1),
+            (Some(_), Some((back, _))) if back <= &key => *self.runs.last_mut().unwrap() += 1,
+            (Some((front, _)), _) if self.runs.len() == 1 && front > &key => {
+                // With a single run there is nothing older that could hold the
+                // same key, so the new item can be put in front.
+                self.runs[0] += 1;
+                self.items.push_front((key, value));
+                return;
+            }
+            _ => self.start_run(1),
+        }
+        self.items.push_back((key, value));
+    }
+
+    pub(crate) fn append(&mut self, thief: SliceThief<(K, V)>, is_sorted: bool) {
+        self.items.reserve(thief.len());
+        if !is_sorted {
+            self.extend_unsorted(thief);
+        } else if thief.len() != 0 {
+            let placement = self.placement(&thief.peek_first().0, &thief.peek_last().0);
+            self.append_placed(thief, placement);
+        }
+    }
+
+    /// Where a sorted slice with keys from `first` to `last` would go.
+    ///
+    /// This is kept apart from [`append_placed`](Buffer::append_placed),
+    /// so that callers can compare keys before moving any items.
+    pub(crate) fn placement(&self, first: &K, last: &K) -> Placement {
+        match (self.items.front(), self.items.back()) {
+            _ if self.is_scattered() => Placement::Back,
+            (Some(_), Some((back, _))) if back <= first => Placement::Back,
+            (Some((front, _)), Some(_)) if self.runs.len() == 1 && front > last => Placement::Front,
+            _ => Placement::NewRun,
+        }
+    }
+
+    /// Appends sorted items where [`placement`](Buffer::placement) put
+    /// them, without comparing any keys.
+    pub(crate) fn append_placed(
+        &mut self,
+        items: impl ExactSizeIterator<Item = (K, V)>,
+        placement: Placement,
+    ) {
+        let len = items.len();
+        if self.is_scattered() {
+            self.items.extend(items);
+            return;
+        }
+        match placement {
+            Placement::Back => *self.runs.last_mut().unwrap() += len,
+            Placement::Front => {
+                self.runs[0] += len;
+                self.items.extend(items);
+                self.items.rotate_right(len);
+                return;
+            }
+            Placement::NewRun => self.start_run(len),
+        }
+        self.items.extend(items);
+    }
+
+    pub(crate) fn extend_unsorted(&mut self, items: impl Iterator<Item = (K, V)>) {
+        for (key, value) in items {
+            if self.is_scattered() {
+                self.items.push_back((key, value));
+            } else {
+                match self.items.back() {
+                    Some((back, _)) if back <= &key => *self.runs.last_mut().unwrap() += 1,
+                    _ => self.start_run(1),
+                }
+                self.items.push_back((key, value));
+            }
+        }
+    }
+
+    fn start_run(&mut self, len: usize) {
+        if self.runs.len() == MAX_RUNS {
+            self.scatter();
+        } else {
+            self.runs.push(len);
+        }
+    }
+
+    /// Sorts the items by key in place and returns them. Items with equal
+    /// keys stay in insertion order.
+    ///
+    /// If `Ord` panics, the items stay in the buffer as they were.
+    pub(crate) fn sorted(&mut self) -> &[(K, V)] {
+        if !self.is_sorted() {
+            let items = self.items.make_contiguous();
+            let mut order = if self.runs.is_empty() {
+                let mut order: Vec<_> = (0..items.len()).collect();
+                order.sort_by(|&i, &j| items[i].0.cmp(&items[j].0));
+                order
+            } else {
+                merge_runs(items, &self.runs)
+            };
+            permute(items, &mut order);
+            self.runs.clear();
+            self.runs.push(self.items.len());
+        }
+        self.items.make_contiguous()
+    }
+
+    /// Empties the buffer, returning its items sorted by key. Items with
+    /// equal keys stay in insertion order.
+    pub(crate) fn take_sorted(&mut self) -> Vec<(K, V)> {
+        self.sorted();
+        self.runs.clear();
+        Vec::from(take(&mut self.items))
+    }
+}
+
+/// The order in which to take the items of consecutive sorted runs,
+/// whose lengths are given by `runs`, to merge them. Ties are broken in
+/// favor of the earlier run.
+///
+/// Only the heads of the runs are compared, so this takes `O(n log k)`
+/// comparisons for `k` runs.
+fn merge_runs<K: Ord, V>(items: &[(K, V)], runs: &[usize]) -> Vec<usize> {
+    let mut heads = BinaryHeap::with_capacity(runs.len());
+    let mut start = 0;
+    for (run, &len) in runs.iter().enumerate() {
+        if len != 0 {
+            heads.push(Head {
+                key: &items[start].0,
+                run,
+                next: start,
+                end: start + len,
+            });
+        }
+        start += len;
+    }
+
+    let mut order = Vec::with_capacity(items.len());
+    while let Some(mut head) = heads.peek_mut() {
+        order.push(head.next);
+        head.next += 1;
+        if head.next == head.end {
+            PeekMut::pop(head);
+        } else {
+            head.key = &items[head.next].0;
+        }
+    }
+    order
+}
+
+/// The next item of a run being merged by [`merge_runs`].
+struct Head<'a, K> {
+    key: &'a K,
+    run: usize,
+    next: usize,
+    end: usize,
+}
+
+impl<K: Ord> Ord for Head<'_, K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed, so that the max-heap yields the smallest key first.
+        other.key.cmp(self.key).then(other.run.cmp(&self.run))
+    }
+}
+
+impl<K: Ord> PartialOrd for Head<'_, K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord> PartialEq for Head<'_, K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl<K: Ord> Eq for Head<'_, K> {}
+
+/// Moves the item at `order[i]` to `i`, for every `i`, without comparing
+/// anything. Each cycle of `order` is followed once, and its entries are
+/// pointed at themselves to mark it as done.
+fn permute<T>(items: &mut [T], order: &mut [usize]) {
+    for start in 0..order.len() {
+        let mut i = start;
+        while order[i] != i {
+            let from = order[i];
+            order[i] = i;
+            if from == start {
+                break;
+            }
+            items.swap(i, from);
+            i = from;
+        }
+    }
+}
+
+/// Where [`Buffer::append_placed`] puts a sorted slice of items.
+#[derive(Clone, Copy)]
+pub(crate) enum Placement {
+    /// At the back, as part of the last run.
+    Back,
+    /// At the front, as part of the only run.
+    Front,
+    /// At the back, as a run of its own.
+    NewRun,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, cmp::Ordering};
+
+    use super::{Buffer, MAX_RUNS};
+    use crate::vec_slicer::VecSlicer;
+
+    fn append(buffer: &mut Buffer<usize, usize>, mut vec: Vec<(usize, usize)>, is_sorted: bool) {
+        buffer.append(VecSlicer::new(&mut vec).slice_to_end(), is_sorted);
+    }
+
+    #[test]
+    fn single_run() {
+        let mut buffer = Buffer::default();
+        for i in 5..10 {
+            buffer.push(i, i);
+        }
+        buffer.push(1, 1);
+        append(&mut buffer, vec![(0, 0)], true);
+        append(&mut buffer, vec![(10, 10), (11, 11)], true);
+        assert_eq!(buffer.runs, [9]);
+
+        let sorted: Vec<_> = buffer.take_sorted().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(sorted, [0, 1, 5, 6, 7, 8, 9, 10, 11]);
+        assert!(buffer.is_empty() && buffer.runs.is_empty());
+    }
+
+    #[test]
+    fn interleaved_batches() {
+        let mut buffer = Buffer::default();
+        for batch in 0..4 {
+            let vec = (0..100).map(|i| (i * 4 + batch, batch)).collect();
+            append(&mut buffer, vec, true);
+        }
+        assert_eq!(buffer.runs, [100; 4]);
+
+        let sorted = buffer.take_sorted();
+        assert!(sorted.iter().enumerate().all(|(i, (k, _))| *k == i));
+    }
+
+    #[test]
+    fn runs_are_merged_without_sorting() {
+        thread_local! {
+            static SAME_RUN_COMPARISONS: Cell<usize> = const { Cell::new(0) };
+        }
+
+        /// A key that remembers the run it was appended in.
+        #[derive(Debug)]
+        struct Key {
+            key: usize,
+            run: usize,
+        }
+
+        impl Ord for Key {
+            fn cmp(&self, other: &Self) -> Ordering {
+                if self.run == other.run {
+                    SAME_RUN_COMPARISONS.set(SAME_RUN_COMPARISONS.get() + 1);
+                }
+                self.key.cmp(&other.key)
+            }
+        }
+
+        impl PartialOrd for Key {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        impl PartialEq for Key {
+            fn eq(&self, other: &Self) -> bool {
+                self.key == other.key
+            }
+        }
+
+        impl Eq for Key {}
+
+        let mut buffer = Buffer::default();
+        for run in 0..8 {
+            let mut vec: Vec<_> = (0..100)
+                .map(|i| {
+                    (
+                        Key {
+                            key: i * 4 + run % 4,
+                            run,
+                        },
+                        run,
+                    )
+                })
+                .collect();
+            buffer.append(VecSlicer::new(&mut vec).slice_to_end(), true);
+        }
+        assert_eq!(buffer.runs, [100; 8]);
+
+        // Sorting would have to compare neighbors within a run to find it.
+        let sorted: Vec<_> = buffer
+            .take_sorted()
+            .into_iter()
+            .map(|(k, v)| (k.key, v))
+            .collect();
+        assert_eq!(SAME_RUN_COMPARISONS.get(), 0);
+        let mut expected: Vec<_> = (0..8)
+            .flat_map(|run| (0..100).map(move |i| (i * 4 + run % 4, run)))
+            .collect();
+        expected.sort_by_key(|(k, _)| *k);
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn duplicates_stay_in_insertion_order() {
+        let mut buffer = Buffer::default();
+        buffer.push(1, 0);
+        buffer.push(2, 0);
+        buffer.push(1, 1);
+        buffer.push(0, 0);
+        buffer.push(1, 2);
+        append(&mut buffer, vec![(2, 1), (1, 3), (1, 4)], false);
+        assert!(buffer.runs.len() > 1);
+
+        let sorted = buffer.take_sorted();
+        assert_eq!(
+            sorted,
+            [
+                (0, 0),
+                (1, 0),
+                (1, 1),
+                (1, 2),
+                (1, 3),
+                (1, 4),
+                (2, 0),
+                (2, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn too_many_runs() {
+        let mut buffer = Buffer::default();
+        let mut expected = vec![];
+        for i in (0..MAX_RUNS * 2).rev() {
+            for item in [(i, i), (i, i + 1), (i + 1, i)] {
+                buffer.push(item.0, item.1);
+                expected.push(item);
+            }
+        }
+        assert!(buffer.is_scattered());
+
+        expected.sort_by_key(|(k, _)| *k);
+        assert_eq!(buffer.take_sorted(), expected);
+    }
+}
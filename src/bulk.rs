@@ -0,0 +1,404 @@
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    iter::{Peekable, once},
+};
+
+use arrayvec::ArrayVec;
+
+use crate::{Array, B, Branch, InternalArray, LeafArray, Map, MaybeBox, Node};
+
+impl<K: Ord, V> Map<K, V> {
+    /// Builds a map bottom-up out of items sorted by key, filling each node
+    /// to `fill_factor` of its capacity.
+    ///
+    /// A fill factor of `1.0` gives the smallest and fastest tree for maps that
+    /// won't change much. Lower fill factors leave room in every node, so that
+    /// later inserts don't immediately split nodes. Nodes get at least two
+    /// items however low the fill factor is.
+    ///
+    /// Items with equal keys must be next to each other, and the last one
+    /// wins. Like inserts, each of them counts towards [`len`](Map::len).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `fill_factor` is not in `(0.0, 1.0]`, or if the items aren't
+    /// sorted by key.
+    pub fn from_sorted(iter: impl IntoIterator<Item = (K, V)>, fill_factor: f64) -> Self {
+        assert!(
+            fill_factor > 0.0 && fill_factor <= 1.0,
+            "fill factor must be in (0.0, 1.0], got {fill_factor}"
+        );
+        let per_node = ((B as f64 * fill_factor).round() as usize).clamp(2, B);
+
+        let mut items = Dedup::new(iter.into_iter(), |_, _| {});
+        let mut map = Map::new();
+        *map.root.get_mut() = build(&mut items, per_node, per_node);
+        map.length = items.count;
+        assert!(!items.unsorted, "items are not sorted by key");
+        map
+    }
+
+    /// Extends the map with items sorted by key.
+    ///
+    /// If the map is empty, the tree is built bottom-up out of fully packed
+    /// nodes, without going through any buffers. Otherwise the items are
+    /// buffered at the root, like [`extend_from_sorted_vec`](Map::extend_from_sorted_vec).
+    ///
+    /// Items with equal keys must be next to each other, and the last one
+    /// wins. Like inserts, each of them counts towards [`len`](Map::len).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the items aren't sorted by key. If the map was empty, it
+    /// then holds the items that came before the first one out of order.
+    pub fn extend_sorted_iter(&mut self, iter: impl IntoIterator<Item = (K, V)>) {
+        if self.is_empty() {
+            self.record_burst();
+            let mut items = Dedup::new(iter.into_iter(), |key, value| self.log(key, value));
+            let root = build(&mut items, B, B);
+            let (count, unsorted) = (items.count, items.unsorted);
+            self.flush_log();
+            *self.root.get_mut() = root;
+            self.length = count;
+            assert!(!unsorted, "items are not sorted by key");
+        } else {
+            let mut vec: Vec<_> = iter.into_iter().collect();
+            assert!(
+                vec.is_sorted_by(|(k1, _), (k2, _)| k1 <= k2),
+                "items are not sorted by key"
+            );
+            self.extend_from_sorted_vec(&mut vec);
+        }
+    }
+
+    /// Extends the map with items in any order. They are buffered at the root,
+    /// like [`extend_from_vec`](Map::extend_from_vec).
+    pub fn extend_iter(&mut self, iter: impl IntoIterator<Item = (K, V)>) {
+        let mut vec: Vec<_> = iter.into_iter().collect();
+        self.extend_from_vec(&mut vec);
+    }
+}
+
+/// Builds a tree bottom-up out of items with increasing keys, putting at
+/// most `per_leaf` items in each leaf and `per_internal` branches in each
+/// internal node.
+pub(crate) fn build<K: Ord, V>(
+    items: impl Iterator<Item = (K, V)>,
+    per_leaf: usize,
+    per_internal: usize,
+) -> Node<K, V> {
+    debug_assert!((2..=B).contains(&per_leaf));
+    debug_assert!((2..=B).contains(&per_internal));
+
+    let mut leaves = vec![ArrayVec::<_, B>::new()];
+    let mut separators = vec![];
+
+    for item in items {
+        let leaf = leaves.last_mut().unwrap();
+        if leaf.len() < per_leaf {
+            leaf.push(item);
+        } else {
+            separators.push(item);
+            leaves.push(ArrayVec::new());
+        }
+    }
+
+    // The last leaf takes whatever was left over, so even it out with its
+    // neighbor if it came up short.
+    if leaves.len() > 1 && leaves.last().unwrap().len() < per_leaf / 2 {
+        let right = leaves.pop().unwrap();
+        let left = leaves.pop().unwrap();
+        let separator = separators.pop().unwrap();
+
+        let mut items: Vec<_> = left
+            .into_iter()
+            .chain(once(separator))
+            .chain(right)
+            .collect();
+        let right = items.drain(items.len() / 2 + 1..).collect();
+        separators.push(items.pop().unwrap());
+        leaves.push(items.into_iter().collect());
+        leaves.push(right);
+    }
+
+    let mut leaves = leaves.into_iter().map(|elements| Node {
+        buffer: Default::default(),
+        array: Array::Leaf(LeafArray {
+            elements: RefCell::new(Box::new(elements)),
+        }),
+    });
+    let mut first = leaves.next().unwrap();
+    let mut rest: Vec<_> = separators.into_iter().zip(leaves).collect();
+
+    while !rest.is_empty() {
+        // Fill the parents like the leaves: each takes `per_internal`
+        // branches, and the last takes whatever is left over, evened out
+        // with its neighbor if it came up short.
+        let children = rest.len() + 1;
+        let full = per_internal + 1;
+        let mut sizes = vec![full; children / full];
+        if children % full != 0 {
+            sizes.push(children % full);
+        }
+        if let [.., left, right] = sizes[..]
+            && right - 1 < per_internal / 2
+        {
+            let total = left + right;
+            sizes.truncate(sizes.len() - 2);
+            sizes.extend([total - total / 2, total / 2]);
+        }
+        let mut rest_iter = rest.into_iter();
+        let mut next_rest = Vec::with_capacity(sizes.len() - 1);
+
+        let mut separator = None;
+        let mut first_child = first;
+        let mut next_first = None;
+        for size in sizes {
+            let elements = rest_iter
+                .by_ref()
+                .take(size - 1)
+                .map(|((key, value), child)| Branch {
+                    key: MaybeBox::Inline(key),
+                    value: MaybeBox::Inline(value),
+                    child: Box::new(child),
+                })
+                .collect();
+            let node = Node {
+                buffer: Default::default(),
+                array: Array::Internal(InternalArray::new(
+                    Box::new(first_child),
+                    Box::new(elements),
+                )),
+            };
+            match separator.take() {
+                None => next_first = Some(node),
+                Some(separator) => next_rest.push((separator, node)),
+            }
+
+            match rest_iter.next() {
+                Some((next_separator, child)) => {
+                    separator = Some(next_separator);
+                    first_child = child;
+                }
+                None => break,
+            }
+        }
+
+        first = next_first.unwrap();
+        rest = next_rest;
+    }
+
+    first
+}
+
+/// Collapses runs of items with equal keys into the last item of the run,
+/// and ends before the first item that is out of order.
+struct Dedup<I: Iterator, F> {
+    iter: Peekable<I>,
+    /// Called on every item once it is known to be in order, including
+    /// those that are collapsed.
+    accept: F,
+    /// The number of items accepted.
+    count: usize,
+    /// Whether the items turned out not to be sorted.
+    unsorted: bool,
+}
+
+impl<K: Ord, V, I: Iterator<Item = (K, V)>, F: FnMut(&K, &V)> Dedup<I, F> {
+    fn new(iter: I, accept: F) -> Self {
+        Dedup {
+            iter: iter.peekable(),
+            accept,
+            count: 0,
+            unsorted: false,
+        }
+    }
+
+    fn accept(&mut self, (key, value): &(K, V)) {
+        (self.accept)(key, value);
+        self.count += 1;
+    }
+}
+
+impl<K: Ord, V, I: Iterator<Item = (K, V)>, F: FnMut(&K, &V)> Iterator for Dedup<I, F> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        if self.unsorted {
+            return None;
+        }
+        let mut item = self.iter.next()?;
+        while let Some(next) = self.iter.peek() {
+            match item.0.cmp(&next.0) {
+                Ordering::Less => break,
+                Ordering::Equal => {
+                    self.accept(&item);
+                    item = self.iter.next().unwrap();
+                }
+                Ordering::Greater => {
+                    self.unsorted = true;
+                    break;
+                }
+            }
+        }
+        self.accept(&item);
+        Some(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Array, B, Map, Node};
+
+    /// The number of items or branches in each node, by level from the
+    /// root, left to right.
+    fn node_sizes<K, V>(node: &Node<K, V>, depth: usize, sizes: &mut Vec<Vec<usize>>) {
+        if sizes.len() == depth {
+            sizes.push(vec![]);
+        }
+        match &node.array {
+            Array::Internal(internal) => {
+                let elements = internal.elements.borrow();
+                sizes[depth].push(elements.len());
+                node_sizes(&internal.first_child, depth + 1, sizes);
+                for branch in elements.iter() {
+                    node_sizes(&branch.child, depth + 1, sizes);
+                }
+            }
+            Array::Leaf(leaf) => sizes[depth].push(leaf.elements.borrow().len()),
+        }
+    }
+
+    #[test]
+    fn from_sorted_fill_factor() {
+        let max = B * B * 3;
+        for fill_factor in [1.0, 0.7, 0.01] {
+            let per_node = ((B as f64 * fill_factor).round() as usize).max(2);
+            let map = Map::from_sorted((0..max).map(|i| (i, i)), fill_factor);
+
+            let mut sizes = vec![];
+            node_sizes(&map.root.borrow(), 0, &mut sizes);
+            // Every level below the root is packed but for its last two
+            // nodes, which are evened out.
+            for level in &sizes[1..] {
+                let (packed, tail) = level.split_last_chunk::<2>().unwrap();
+                assert!(packed.iter().all(|size| *size == per_node));
+                assert!(tail.iter().all(|size| *size >= per_node / 2));
+            }
+
+            for i in 0..max {
+                assert_eq!(map.get(&i), Some(&i));
+            }
+        }
+    }
+
+    #[test]
+    fn from_sorted_tiny_fill_factor() {
+        for len in [1, 2, 3, 4, 5, 10, B * 3 + 1] {
+            let map = Map::from_sorted((0..len).map(|i| (i, i)), 0.001);
+            map.check_invariants().unwrap();
+            assert_eq!(map.len(), len);
+            for i in 0..len {
+                assert_eq!(map.get(&i), Some(&i));
+            }
+        }
+    }
+
+    #[test]
+    fn from_sorted_then_insert() {
+        let max = B * B;
+        let mut map = Map::from_sorted((0..max).map(|i| (i * 2, i)), 0.7);
+        for i in 0..max {
+            map.insert(i * 2 + 1, i);
+        }
+        for i in 0..max {
+            assert_eq!(map.get(&(i * 2)), Some(&i));
+            assert_eq!(map.get(&(i * 2 + 1)), Some(&i));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn from_sorted_zero_fill_factor() {
+        Map::from_sorted([(1, 1)], 0.0);
+    }
+
+    #[test]
+    #[should_panic = "not sorted"]
+    fn from_sorted_unsorted() {
+        Map::from_sorted([(1, 1), (3, 3), (2, 2)], 1.0);
+    }
+
+    #[test]
+    #[should_panic = "not sorted"]
+    fn extend_sorted_iter_unsorted() {
+        let mut map = Map::new();
+        map.insert(0, 0);
+        map.extend_sorted_iter([(1, 1), (3, 3), (2, 2)]);
+    }
+
+    #[test]
+    fn extend_sorted_iter_unsorted_into_empty() {
+        let mut map = Map::new();
+        let items = (0..B * 3).chain([B, B * 4]).map(|i| (i, i));
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            map.extend_sorted_iter(items);
+        }));
+        assert!(result.is_err());
+        assert_eq!(map.len(), B * 3);
+        map.check_invariants().unwrap();
+        assert_eq!(map.get(&(B * 3 - 1)), Some(&(B * 3 - 1)));
+        assert_eq!(map.get(&(B * 4)), None);
+    }
+
+    #[test]
+    fn extend_sorted_iter_sizes() {
+        for n in [0, 1, B - 1, B, B + 1, B * 2 + 1, B * B, B * B * 3 + 7] {
+            let mut map = Map::new();
+            map.extend_sorted_iter((0..n).map(|i| (i * 2, i)));
+            assert_eq!(map.len(), n);
+            for i in 0..n {
+                assert_eq!(map.get(&(i * 2)), Some(&i));
+                assert_eq!(map.get(&(i * 2 + 1)), None);
+                assert_eq!(map.get_before(&(i * 2 + 1)), Some(&i));
+            }
+        }
+    }
+
+    #[test]
+    fn extend_sorted_iter_duplicates() {
+        let mut map = Map::new();
+        map.extend_sorted_iter((0..B * B).flat_map(|i| [(i / 3, i), (i / 3, i + 1)]));
+        // Like inserts, duplicates count towards the length.
+        assert_eq!(map.len(), B * B * 2);
+        for i in 0..B * B / 3 {
+            assert_eq!(map.get(&i), Some(&(i * 3 + 3)));
+        }
+    }
+
+    #[test]
+    fn extend_sorted_iter_then_insert() {
+        let mut map = Map::new();
+        let max = B * B * 3;
+        map.extend_sorted_iter((0..max).map(|i| (i * 2, i)));
+        for i in 0..max {
+            map.insert(i * 2 + 1, i);
+        }
+        map.extend_sorted_iter((0..max).map(|i| (i * 2, i + 1)));
+        map.flush();
+        for i in 0..max {
+            assert_eq!(map.get(&(i * 2)), Some(&(i + 1)));
+            assert_eq!(map.get(&(i * 2 + 1)), Some(&i));
+        }
+    }
+
+    #[test]
+    fn extend_iter() {
+        let mut map = Map::new();
+        map.extend_iter((0..B * 3).rev().map(|i| (i, i)));
+        map.extend_iter([(1, 0)]);
+        assert_eq!(map.get(&1), Some(&0));
+        assert_eq!(map.get(&(B * 3 - 1)), Some(&(B * 3 - 1)));
+    }
+}
@@ -0,0 +1,204 @@
+use std::{error::Error, fmt};
+
+use crate::{Array, Map, Node};
+
+/// A broken invariant found by [`Map::check_invariants`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvariantError {
+    /// The child indices leading from the root to the offending node, where
+    /// `0` is the child before the first separator and `i` is the child after
+    /// the `i`th.
+    pub path: Vec<usize>,
+    pub violation: Violation,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Violation {
+    /// The array element at `index` isn't greater than the one before it.
+    UnsortedArray { index: usize },
+    /// The array element at `index` isn't strictly between the separators
+    /// around the node.
+    ArrayOutOfBounds { index: usize },
+    /// The buffered item at `index`, in insertion order, isn't strictly
+    /// between the separators around the node.
+    BufferOutOfBounds { index: usize },
+    /// An internal node has no separators.
+    EmptyInternal,
+    /// A leaf isn't as deep as the first leaf.
+    UnevenDepth { depth: usize, expected: usize },
+}
+
+impl fmt::Display for InvariantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "node at {:?}: ", self.path)?;
+        match self.violation {
+            Violation::UnsortedArray { index } => {
+                write!(f, "element {index} is not after the one before it")
+            }
+            Violation::ArrayOutOfBounds { index } => {
+                write!(f, "element {index} is outside of its separators")
+            }
+            Violation::BufferOutOfBounds { index } => {
+                write!(f, "buffered item {index} is outside of its separators")
+            }
+            Violation::EmptyInternal => f.write_str("internal node has no separators"),
+            Violation::UnevenDepth { depth, expected } => {
+                write!(f, "leaf is at depth {depth} instead of {expected}")
+            }
+        }
+    }
+}
+
+impl Error for InvariantError {}
+
+impl<K: Ord, V> Map<K, V> {
+    /// Walks the whole tree and checks that it is well formed: every
+    /// separator bounds the keys and buffered items below it, every array
+    /// is sorted, and every leaf is at the same depth.
+    ///
+    /// Arrays never exceed `B` elements, since their capacity is fixed.
+    /// Doesn't push down any buffers.
+    pub fn check_invariants(&self) -> Result<(), InvariantError> {
+        let mut checker = Checker {
+            path: vec![],
+            leaf_depth: None,
+        };
+        checker
+            .check(&self.root.borrow(), None, None)
+            .map_err(|violation| InvariantError {
+                path: checker.path,
+                violation,
+            })
+    }
+}
+
+struct Checker {
+    path: Vec<usize>,
+    leaf_depth: Option<usize>,
+}
+
+impl Checker {
+    /// Checks `node`, whose keys must be strictly between `lower` and
+    /// `upper`. On failure, leaves `path` pointing at the offending node.
+    fn check<K: Ord, V>(
+        &mut self,
+        node: &Node<K, V>,
+        lower: Option<&K>,
+        upper: Option<&K>,
+    ) -> Result<(), Violation> {
+        let in_bounds = |key: &K| {
+            lower.is_none_or(|lower| lower < key) && upper.is_none_or(|upper| key < upper)
+        };
+
+        if let Some(index) = node
+            .buffer
+            .borrow()
+            .iter()
+            .position(|(key, _)| !in_bounds(key))
+        {
+            return Err(Violation::BufferOutOfBounds { index });
+        }
+
+        match &node.array {
+            Array::Internal(internal) => {
+                let elements = internal.elements.borrow();
+                if elements.is_empty() {
+                    return Err(Violation::EmptyInternal);
+                }
+                check_array(elements.iter().map(|branch| &*branch.key), in_bounds)?;
+
+                let mut separators = elements.iter().map(|branch| &*branch.key);
+                self.path.push(0);
+                self.check(&internal.first_child, lower, separators.next())?;
+                for (i, branch) in elements.iter().enumerate() {
+                    *self.path.last_mut().unwrap() = i + 1;
+                    self.check(
+                        &branch.child,
+                        Some(&*branch.key),
+                        separators.next().or(upper),
+                    )?;
+                }
+                self.path.pop();
+            }
+            Array::Leaf(leaf) => {
+                check_array(leaf.elements.borrow().iter().map(|(key, _)| key), in_bounds)?;
+                let depth = self.path.len();
+                match self.leaf_depth {
+                    None => self.leaf_depth = Some(depth),
+                    Some(expected) if expected != depth => {
+                        return Err(Violation::UnevenDepth { depth, expected });
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_array<'a, K: Ord + 'a>(
+    keys: impl Iterator<Item = &'a K>,
+    in_bounds: impl Fn(&K) -> bool,
+) -> Result<(), Violation> {
+    let mut previous = None;
+    for (index, key) in keys.enumerate() {
+        if previous.is_some_and(|previous| previous >= key) {
+            return Err(Violation::UnsortedArray { index });
+        }
+        if !in_bounds(key) {
+            return Err(Violation::ArrayOutOfBounds { index });
+        }
+        previous = Some(key);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Violation;
+    use crate::{Array, B, Map};
+
+    fn settled_map() -> Map<usize, usize> {
+        let mut map = Map::new();
+        map.extend_sorted_iter((0..B * B * 2).map(|i| (i * 2, i)));
+        map.insert(7, 7);
+        map.flush();
+        map.insert(5, 5);
+        map.check_invariants().unwrap();
+        map
+    }
+
+    #[test]
+    fn misplaced_buffer_item() {
+        let map = settled_map();
+        {
+            let root = map.root.borrow();
+            let Array::Internal(internal) = &root.array else {
+                panic!("root should be internal");
+            };
+            internal.first_child.insert(B * B * 8, 0);
+        }
+        let error = map.check_invariants().unwrap_err();
+        assert_eq!(error.path, [0]);
+        assert_eq!(error.violation, Violation::BufferOutOfBounds { index: 0 });
+    }
+
+    #[test]
+    fn unsorted_separators() {
+        let map = settled_map();
+        {
+            let root = map.root.borrow();
+            let Array::Internal(internal) = &root.array else {
+                panic!("root should be internal");
+            };
+            let Array::Internal(child) = &internal.first_child.array else {
+                panic!("first child should be internal");
+            };
+            child.elements.borrow_mut().swap(0, 1);
+        }
+        let error = map.check_invariants().unwrap_err();
+        assert_eq!(error.path, [0]);
+        assert_eq!(error.violation, Violation::UnsortedArray { index: 1 });
+    }
+}
@@ -0,0 +1,617 @@
+use std::{
+    cell::RefCell,
+    error::Error,
+    fmt,
+    io::{self, Read, Write},
+};
+
+use arrayvec::ArrayVec;
+
+use crate::{
+    Array, B, Branch, InternalArray, LeafArray, Map, MaybeBox, Node, buffer::Buffer, bulk::build,
+};
+
+const MAGIC: [u8; 4] = *b"LZBT";
+const VERSION: u16 = 1;
+
+/// Guards against stack overflows on corrupt input. A tree of this
+/// depth would hold far more items than fit in memory.
+const MAX_DEPTH: usize = 64;
+
+/// How a key or value is turned into bytes by [`Map::write_to`] and back
+/// by [`Map::read_from`].
+pub trait Codec: Sized {
+    /// Appends the encoding of `self` to `out`.
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Decodes a value from exactly the bytes written by [`encode`](Codec::encode),
+    /// or returns `None` if they don't hold a valid value.
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! int_codec {
+    ($($int:ty),*) => {$(
+        impl Codec for $int {
+            fn encode(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn decode(bytes: &[u8]) -> Option<Self> {
+                Some(<$int>::from_le_bytes(bytes.try_into().ok()?))
+            }
+        }
+    )*};
+}
+
+int_codec!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl Codec for usize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u64).encode(out);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        u64::decode(bytes)?.try_into().ok()
+    }
+}
+
+impl Codec for isize {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as i64).encode(out);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        i64::decode(bytes)?.try_into().ok()
+    }
+}
+
+impl Codec for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(u8::from(*self));
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
+impl Codec for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+impl Codec for Vec<u8> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+impl Codec for () {
+    fn encode(&self, _out: &mut Vec<u8>) {}
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        bytes.is_empty().then_some(())
+    }
+}
+
+/// Why [`Map::read_from`] failed.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ReadError {
+    /// The reader failed.
+    Io(io::Error),
+    /// The input ended in the middle of the map.
+    Truncated,
+    /// The input doesn't start like a serialized map.
+    BadMagic,
+    /// The input was written by an incompatible version of this crate.
+    UnsupportedVersion(u16),
+    /// The input doesn't match the checksum written along with it.
+    ChecksumMismatch,
+    /// The contents don't describe a valid map. Besides damaged input, this
+    /// happens when the codecs used for writing and reading differ.
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(error) => write!(f, "failed to read map: {error}"),
+            ReadError::Truncated => f.write_str("serialized map is truncated"),
+            ReadError::BadMagic => f.write_str("input is not a serialized map"),
+            ReadError::UnsupportedVersion(version) => {
+                write!(f, "unsupported serialized map version {version}")
+            }
+            ReadError::ChecksumMismatch => f.write_str("serialized map fails its checksum"),
+            ReadError::Corrupt(what) => write!(f, "serialized map is corrupt: {what}"),
+        }
+    }
+}
+
+impl Error for ReadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReadError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::UnexpectedEof {
+            ReadError::Truncated
+        } else {
+            ReadError::Io(error)
+        }
+    }
+}
+
+#[repr(u8)]
+enum Layout {
+    /// The items in key order, to be loaded bottom-up.
+    Sorted = 0,
+    /// Every node as it is, including the items in its buffer.
+    Structure = 1,
+}
+
+impl<K: Ord + Codec, V: Codec> Map<K, V> {
+    /// Flushes the map and writes its items to `writer` in key order.
+    ///
+    /// The output holds the number of items and then the items, prefixed
+    /// with a format version and followed by a CRC-32 checksum. Each key and value
+    /// is length-prefixed, so codecs don't need to delimit their output.
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        self.flush();
+        let mut writer = Encoder::new(writer);
+        writer.header(Layout::Sorted)?;
+        let root = self.root.borrow();
+        writer.u64(root.count_settled() as u64)?;
+        root.try_for_each_settled(&mut |key, value| {
+            writer.encode(key)?;
+            writer.encode(value)
+        })?;
+        writer.finish()
+    }
+
+    /// Writes every node of the map to `writer` as it is, without flushing.
+    ///
+    /// This takes more space than [`write_to`](Map::write_to), but keeps
+    /// the buffered items buffered, so that reading the map back doesn't
+    /// repeat the work that was put off.
+    pub fn write_structure_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut writer = Encoder::new(writer);
+        writer.header(Layout::Structure)?;
+        writer.u64(self.length as u64)?;
+        write_node(&self.root.borrow(), &mut writer)?;
+        writer.finish()
+    }
+
+    /// Reads a map written by [`write_to`](Map::write_to) or
+    /// [`write_structure_to`](Map::write_structure_to).
+    ///
+    /// The input is read up to the end of the checksum, and nothing
+    /// after it is consumed.
+    pub fn read_from(reader: impl Read) -> Result<Self, ReadError> {
+        let mut reader = Decoder::new(reader);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(ReadError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(ReadError::UnsupportedVersion(version));
+        }
+
+        let mut map = Map::new();
+        match reader.u8()? {
+            layout if layout == Layout::Sorted as u8 => {
+                let count = reader.u64()?;
+                let mut items = Vec::new();
+                for _ in 0..count {
+                    let item = (reader.decode()?, reader.decode()?);
+                    if items.last().is_some_and(|(last, _)| last >= &item.0) {
+                        return Err(ReadError::Corrupt("keys out of order"));
+                    }
+                    items.push(item);
+                }
+                reader.finish()?;
+                map.length = items.len();
+                *map.root.get_mut() = build(items.into_iter(), B, B);
+            }
+            layout if layout == Layout::Structure as u8 => {
+                let length = reader.u64()?;
+                let (root, dirty) = read_node(&mut reader, 0)?;
+                reader.finish()?;
+                *map.root.get_mut() = root;
+                map.length = usize::try_from(length)
+                    .map_err(|_| ReadError::Corrupt("length out of range"))?;
+                if dirty {
+                    map.mark_dirty();
+                }
+            }
+            _ => return Err(ReadError::Corrupt("unknown layout")),
+        }
+        Ok(map)
+    }
+}
+
+fn write_node<K: Codec, V: Codec>(
+    node: &Node<K, V>,
+    writer: &mut Encoder<impl Write>,
+) -> io::Result<()> {
+    let buffer = node.buffer.borrow();
+    writer.u64(buffer.len() as u64)?;
+    for (key, value) in buffer.iter() {
+        writer.encode(key)?;
+        writer.encode(value)?;
+    }
+
+    match &node.array {
+        Array::Internal(internal) => {
+            let elements = internal.elements.borrow();
+            writer.u8(1)?;
+            writer.u64(elements.len() as u64)?;
+            write_node(&internal.first_child, writer)?;
+            for branch in elements.iter() {
+                writer.encode(&*branch.key)?;
+                writer.encode(&*branch.value)?;
+                write_node(&branch.child, writer)?;
+            }
+        }
+        Array::Leaf(leaf) => {
+            let elements = leaf.elements.borrow();
+            writer.u8(0)?;
+            writer.u64(elements.len() as u64)?;
+            for (key, value) in elements.iter() {
+                writer.encode(key)?;
+                writer.encode(value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a node written by [`write_node`], and returns it along with
+/// whether any buffer in it holds items.
+fn read_node<K: Ord + Codec, V: Codec>(
+    reader: &mut Decoder<impl Read>,
+    depth: usize,
+) -> Result<(Node<K, V>, bool), ReadError> {
+    if depth > MAX_DEPTH {
+        return Err(ReadError::Corrupt("tree too deep"));
+    }
+
+    let mut buffer = Buffer::default();
+    let buffered = reader.u64()?;
+    for _ in 0..buffered {
+        buffer.push(reader.decode()?, reader.decode()?);
+    }
+    let mut dirty = buffered > 0;
+
+    let tag = reader.u8()?;
+    let len = reader.u64()?;
+    if len > B as u64 {
+        return Err(ReadError::Corrupt("node too large"));
+    }
+    let array = match tag {
+        0 => {
+            let mut elements = Box::new(ArrayVec::<(K, V), B>::new());
+            for _ in 0..len {
+                let item: (K, V) = (reader.decode()?, reader.decode()?);
+                if elements.last().is_some_and(|(last, _)| last >= &item.0) {
+                    return Err(ReadError::Corrupt("keys out of order"));
+                }
+                elements.push(item);
+            }
+            Array::Leaf(LeafArray {
+                elements: RefCell::new(elements),
+            })
+        }
+        1 => {
+            let (first_child, first_dirty) = read_node(reader, depth + 1)?;
+            dirty |= first_dirty;
+            let mut elements = Box::new(ArrayVec::<Branch<K, V>, B>::new());
+            for _ in 0..len {
+                let key: K = reader.decode()?;
+                let value = reader.decode()?;
+                if elements.last().is_some_and(|last| *last.key >= key) {
+                    return Err(ReadError::Corrupt("keys out of order"));
+                }
+                let (child, child_dirty) = read_node(reader, depth + 1)?;
+                dirty |= child_dirty;
+                elements.push(Branch {
+                    key: MaybeBox::Inline(key),
+                    value: MaybeBox::Inline(value),
+                    child: Box::new(child),
+                });
+            }
+            Array::Internal(InternalArray::new(Box::new(first_child), elements))
+        }
+        _ => return Err(ReadError::Corrupt("unknown node kind")),
+    };
+    Ok((
+        Node {
+            buffer: RefCell::new(buffer),
+            array,
+        },
+        dirty,
+    ))
+}
+
+/// Writes the parts of the format, keeping a checksum of everything written.
+pub(crate) struct Encoder<W> {
+    inner: W,
+    crc: Crc32,
+    scratch: Vec<u8>,
+}
+
+impl<W: Write> Encoder<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        Encoder {
+            inner,
+            crc: Crc32::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.crc.update(bytes);
+        self.inner.write_all(bytes)
+    }
+
+    fn header(&mut self, layout: Layout) -> io::Result<()> {
+        self.write_all(&MAGIC)?;
+        self.write_all(&VERSION.to_le_bytes())?;
+        self.u8(layout as u8)
+    }
+
+    pub(crate) fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.write_all(&[value])
+    }
+
+    pub(crate) fn u64(&mut self, value: u64) -> io::Result<()> {
+        self.write_all(&value.to_le_bytes())
+    }
+
+    pub(crate) fn encode(&mut self, value: &impl Codec) -> io::Result<()> {
+        let mut scratch = std::mem::take(&mut self.scratch);
+        scratch.clear();
+        value.encode(&mut scratch);
+        let len = u32::try_from(scratch.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "encoded item too large"))?;
+        self.write_all(&len.to_le_bytes())?;
+        self.write_all(&scratch)?;
+        self.scratch = scratch;
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        let crc = self.crc.finish();
+        self.inner.write_all(&crc.to_le_bytes())?;
+        self.inner.flush()
+    }
+}
+
+/// Reads the parts of the format, keeping a checksum of everything read.
+pub(crate) struct Decoder<R> {
+    inner: R,
+    crc: Crc32,
+    scratch: Vec<u8>,
+}
+
+impl<R: Read> Decoder<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Decoder {
+            inner,
+            crc: Crc32::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    fn read_exact(&mut self, bytes: &mut [u8]) -> Result<(), ReadError> {
+        self.inner.read_exact(bytes)?;
+        self.crc.update(bytes);
+        Ok(())
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, ReadError> {
+        let mut bytes = [0; 1];
+        self.read_exact(&mut bytes)?;
+        Ok(bytes[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ReadError> {
+        let mut bytes = [0; 2];
+        self.read_exact(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, ReadError> {
+        let mut bytes = [0; 4];
+        self.read_exact(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, ReadError> {
+        let mut bytes = [0; 8];
+        self.read_exact(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn decode<T: Codec>(&mut self) -> Result<T, ReadError> {
+        let len = self.u32()?;
+        self.scratch.clear();
+        // Read through `take` rather than into a buffer of `len` bytes, so
+        // that a corrupt length can't make us allocate more than the input.
+        (&mut self.inner)
+            .take(len.into())
+            .read_to_end(&mut self.scratch)?;
+        if self.scratch.len() != len as usize {
+            return Err(ReadError::Truncated);
+        }
+        self.crc.update(&self.scratch);
+        T::decode(&self.scratch).ok_or(ReadError::Corrupt("undecodable key or value"))
+    }
+
+    /// Checks the checksum at the end of the input.
+    pub(crate) fn finish(mut self) -> Result<(), ReadError> {
+        let expected = self.crc.finish();
+        let mut bytes = [0; 4];
+        self.inner.read_exact(&mut bytes)?;
+        if u32::from_le_bytes(bytes) == expected {
+            Ok(())
+        } else {
+            Err(ReadError::ChecksumMismatch)
+        }
+    }
+}
+
+/// CRC-32 as used by zlib and PNG.
+struct Crc32(u32);
+
+impl Crc32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    fn new() -> Self {
+        Crc32(!0)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = Self::TABLE[((self.0 ^ u32::from(*byte)) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Crc32, ReadError};
+    use crate::{B, Map};
+
+    fn sample() -> Map<u32, String> {
+        let mut map = Map::new();
+        for i in 0..(B * B * 2) as u32 {
+            map.insert(i * 2, i.to_string());
+        }
+        map.flush();
+        for i in 0..100 {
+            map.insert(i * 3, String::from("new"));
+        }
+        map
+    }
+
+    fn check(map: &Map<u32, String>) {
+        for i in 0..(B * B * 2) as u32 {
+            let expected = if i < 300 && i % 3 == 0 {
+                String::from("new")
+            } else if i % 2 == 0 {
+                (i / 2).to_string()
+            } else {
+                assert_eq!(map.get(&i), None);
+                continue;
+            };
+            assert_eq!(map.get(&i), Some(&expected));
+        }
+    }
+
+    #[test]
+    fn crc32() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trip_sorted() {
+        let mut bytes = vec![];
+        sample().write_to(&mut bytes).unwrap();
+        let map = Map::read_from(&bytes[..]).unwrap();
+        assert_eq!(map.len(), B * B * 2 + 50);
+        check(&map);
+    }
+
+    #[test]
+    fn round_trip_structure() {
+        let original = sample();
+        let mut bytes = vec![];
+        original.write_structure_to(&mut bytes).unwrap();
+        let map = Map::<u32, String>::read_from(&bytes[..]).unwrap();
+        assert_eq!(map.len(), original.len());
+        assert!(map.flush_step(1));
+        check(&map);
+    }
+
+    #[test]
+    fn round_trip_empty() {
+        let mut bytes = vec![];
+        Map::<u8, ()>::new().write_to(&mut bytes).unwrap();
+        let map = Map::<u8, ()>::read_from(&bytes[..]).unwrap();
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn bad_input() {
+        let mut bytes = vec![];
+        sample().write_to(&mut bytes).unwrap();
+
+        let read = |bytes: &[u8]| Map::<u32, String>::read_from(bytes).err().unwrap();
+        assert!(matches!(
+            read(&bytes[..bytes.len() - 1]),
+            ReadError::Truncated
+        ));
+        assert!(matches!(read(&bytes[..100]), ReadError::Truncated));
+        assert!(matches!(read(b"nope"), ReadError::BadMagic));
+
+        let mut version = bytes.clone();
+        version[4] = 9;
+        assert!(matches!(read(&version), ReadError::UnsupportedVersion(9)));
+
+        let mut flipped = bytes.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert!(matches!(read(&flipped), ReadError::ChecksumMismatch));
+        let mut flipped = bytes.clone();
+        flipped[bytes.len() / 2] ^= 1;
+        assert!(read(&flipped).to_string().contains("serialized map"));
+
+        // Decoding with a different value codec.
+        let err = Map::<u32, u64>::read_from(&bytes[..]).err().unwrap();
+        assert!(matches!(err, ReadError::Corrupt(_)));
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    fmt::{self, Debug, Display},
+    io::{self, Write},
+};
+
+use crate::{Array, Branch, Map, MaybeBox, Node, buffer::Buffer};
+
+impl<K: Debug, V: Debug> Map<K, V> {
+    /// Writes the tree in Graphviz's DOT language, one record per node.
+    ///
+    /// Each record shows the node's buffer and whether it is sorted, then
+    /// its elements. Internal nodes have a port between each pair of
+    /// separators, with an edge to the matching child.
+    pub fn to_dot(&self, writer: &mut impl Write) -> io::Result<()> {
+        writeln!(writer, "digraph {{")?;
+        writeln!(writer, "    node [shape=record];")?;
+        let mut next_id = 0;
+        self.root.borrow().write_dot(writer, &mut next_id)?;
+        writeln!(writer, "}}")
+    }
+
+    /// An indented text rendering of the tree, with the same details as
+    /// [`to_dot`](Map::to_dot).
+    ///
+    /// ```text
+    /// internal, buffer (sorted): [(9, 9)]
+    ///     leaf [(0, 0), (1, 1)], buffer (sorted): []
+    ///     separator 2 => 2 (boxed value)
+    ///     leaf [(3, 3)], buffer (unsorted): [(4, 4), (6, 6), (5, 5)]
+    /// ```
+    pub fn dump(&self) -> impl Display + '_ {
+        Dump(self)
+    }
+}
+
+struct Dump<'a, K, V>(&'a Map<K, V>);
+
+impl<K: Debug, V: Debug> Display for Dump<'_, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.root.borrow().write_text(f, 0)
+    }
+}
+
+impl<K: Debug, V: Debug> Node<K, V> {
+    fn write_text(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        let indent = "    ".repeat(depth);
+        let buffer = describe_buffer(&self.buffer.borrow());
+        match &self.array {
+            Array::Internal(internal) => {
+                writeln!(f, "{indent}internal, {buffer}")?;
+                internal.first_child.write_text(f, depth + 1)?;
+                for branch in internal.elements.borrow().iter() {
+                    writeln!(f, "{indent}    separator {}", describe_branch(branch))?;
+                    branch.child.write_text(f, depth + 1)?;
+                }
+                Ok(())
+            }
+            Array::Leaf(leaf) => {
+                let elements = leaf.elements.borrow();
+                writeln!(f, "{indent}leaf {:?}, {buffer}", elements.as_slice())
+            }
+        }
+    }
+
+    /// Writes this node and its subtree, and returns this node's id.
+    fn write_dot(&self, writer: &mut impl Write, next_id: &mut usize) -> io::Result<usize> {
+        let id = *next_id;
+        *next_id += 1;
+        let buffer = escape(&describe_buffer(&self.buffer.borrow()));
+        match &self.array {
+            Array::Internal(internal) => {
+                let elements = internal.elements.borrow();
+                let mut fields = String::from("<c0>");
+                for (i, branch) in elements.iter().enumerate() {
+                    fields += &format!("|{}|<c{}>", escape(&describe_branch(branch)), i + 1);
+                }
+                writeln!(writer, "    n{id} [label=\"{{{buffer}|{{{fields}}}}}\"];")?;
+
+                let children = std::iter::once(&internal.first_child)
+                    .chain(elements.iter().map(|branch| &branch.child));
+                for (i, child) in children.enumerate() {
+                    let child_id = child.write_dot(writer, next_id)?;
+                    writeln!(writer, "    n{id}:c{i} -> n{child_id};")?;
+                }
+            }
+            Array::Leaf(leaf) => {
+                let fields = leaf
+                    .elements
+                    .borrow()
+                    .iter()
+                    .map(|item| escape(&format!("{item:?}")))
+                    .collect::<Vec<_>>()
+                    .join("|");
+                writeln!(writer, "    n{id} [label=\"{{{buffer}|{{{fields}}}}}\"];")?;
+            }
+        }
+        Ok(id)
+    }
+}
+
+fn describe_buffer<K: Debug, V: Debug>(buffer: &Buffer<K, V>) -> String {
+    let sorted = if buffer.is_sorted() {
+        "sorted"
+    } else {
+        "unsorted"
+    };
+    let items: Vec<_> = buffer.iter().collect();
+    format!("buffer ({sorted}): {items:?}")
+}
+
+fn describe_branch<K: Debug, V: Debug>(branch: &Branch<K, V>) -> String {
+    let mut description = format!("{:?} => {:?}", *branch.key, *branch.value);
+    if matches!(branch.value, MaybeBox::Boxed(_)) {
+        description += " (boxed value)";
+    }
+    if matches!(branch.key, MaybeBox::Boxed(_)) {
+        description += " (boxed key)";
+    }
+    description
+}
+
+/// Escapes the characters that have a meaning in DOT record labels.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '{' | '}' | '|' | '<' | '>' | '"' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{B, Map};
+
+    #[test]
+    fn dump_small() {
+        let mut map = Map::new();
+        map.insert(2, "b");
+        map.insert(1, "a");
+        assert_eq!(
+            map.dump().to_string(),
+            "leaf [], buffer (sorted): [(1, \"a\"), (2, \"b\")]\n"
+        );
+        map.flush();
+        map.insert(3, "c");
+        map.insert(5, "e");
+        map.insert(4, "d");
+        assert_eq!(
+            map.dump().to_string(),
+            "leaf [(1, \"a\"), (2, \"b\")], buffer (unsorted): [(3, \"c\"), (5, \"e\"), (4, \"d\")]\n"
+        );
+    }
+
+    #[test]
+    fn dump_boxed() {
+        let mut map = Map::new();
+        map.extend_sorted_iter((0..B * 2).map(|i| (i, i)));
+        map.flush();
+        let separator = (0..B * 2)
+            .find(|i| map.dump().to_string().contains(&format!("separator {i} ")))
+            .unwrap();
+        map.get(&separator);
+        let dump = map.dump().to_string();
+        assert!(dump.starts_with("internal, buffer (sorted): []\n    leaf [(0, 0), "));
+        assert!(dump.contains(&format!(
+            "separator {separator} => {separator} (boxed value)\n"
+        )));
+    }
+
+    #[test]
+    fn dot() {
+        let mut map = Map::new();
+        map.extend_sorted_iter((0..B * 2).map(|i| (i, i.to_string())));
+        map.flush();
+        map.insert(B * 3, String::from("{|}"));
+        let mut dot = vec![];
+        map.to_dot(&mut dot).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert!(dot.starts_with(
+            "digraph {\n    node [shape=record];\n    n0 [label=\"{buffer (sorted): [("
+        ));
+        assert!(dot.contains("\\\"\\{\\|\\}\\\""));
+        assert!(dot.contains("    n0:c0 -> n1;\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+}
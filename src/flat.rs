@@ -0,0 +1,479 @@
+//! Building blocks for the tree variants that keep the items of each node
+//! in a plain `Vec`, with children in a separate `Vec`: [`SyncMap`],
+//! [`PersistentMap`], [`PagedMap`], and the internal levels of
+//! [`BPlusMap`].
+//!
+//! Each variant settles its nodes the same way. A leaf merges its buffer
+//! into its items with [`settle_leaf`]. An internal node hands its buffer
+//! out to its children with [`push_down`], settles some of them with
+//! [`settle_children`], and then [`split`]s if it overflows.
+//!
+//! Like [`Map`](crate::Map), these hold a panic from the keys' `Ord` back
+//! with [`unwind::catch`] and compare everything before moving any item,
+//! so that a panic leaves every item in some buffer or node. The variants
+//! rethrow the panic with [`unwind::resume`] once they let go of their
+//! nodes.
+//!
+//! [`SyncMap`]: crate::SyncMap
+//! [`PersistentMap`]: crate::PersistentMap
+//! [`PagedMap`]: crate::PagedMap
+//! [`BPlusMap`]: crate::BPlusMap
+
+use std::{
+    cmp::Ordering,
+    iter,
+    mem::{replace, take},
+    ops::Range,
+};
+
+use crate::{
+    B,
+    buffer::{Buffer, Placement},
+    unwind,
+    vec_slicer::{SliceThief, VecSlicer},
+};
+
+#[derive(Clone)]
+pub(crate) struct FlatNode<K, V, C> {
+    pub(crate) buffer: Buffer<K, V>,
+    pub(crate) elements: Vec<(K, V)>,
+    /// Empty for leaves. Otherwise there is one more child than elements.
+    pub(crate) children: Vec<C>,
+}
+
+impl<K, V, C> FlatNode<K, V, C> {
+    pub(crate) fn new(elements: Vec<(K, V)>, children: Vec<C>) -> Self {
+        FlatNode {
+            buffer: Buffer::default(),
+            elements,
+            children,
+        }
+    }
+
+    pub(crate) fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+}
+
+pub(crate) fn search<K: Ord, V>(elements: &[(K, V)], key: &K) -> Result<usize, usize> {
+    elements.binary_search_by(|(k, _)| k.cmp(key))
+}
+
+/// The children of a node with separators `elements` that hold the path
+/// to `key`, or all of them if `key` is `None`. If `key` is a separator,
+/// its path ends at the node, so there are none.
+///
+/// A panic from `Ord` is held back, and then there are none either.
+pub(crate) fn children_toward<K: Ord, V>(elements: &[(K, V)], key: Option<&K>) -> Range<usize> {
+    match key {
+        None => 0..elements.len() + 1,
+        Some(key) => match unwind::catch(|| search(elements, key)) {
+            Some(Err(i)) => i..i + 1,
+            Some(Ok(_)) | None => 0..0,
+        },
+    }
+}
+
+/// Adds unsorted `items` to the back of `buffer`, like
+/// [`Buffer::extend_unsorted`], but placing one item at a time, so that
+/// a panic from `Ord` can't drop the items not yet placed. It is held
+/// back, and the items after it start runs of their own.
+pub(crate) fn extend_buffer<K: Ord, V>(buffer: &mut Buffer<K, V>, items: Vec<(K, V)>) {
+    for (key, value) in items {
+        let placement = match unwind::catch(|| buffer.placement(&key, &key)) {
+            Some(Placement::Back) => Placement::Back,
+            _ => Placement::NewRun,
+        };
+        buffer.append_placed(iter::once((key, value)), placement);
+    }
+}
+
+/// Adds a sorted `slice` to `buffer`, like [`Buffer::append`]. A panic
+/// from `Ord` is held back, and the slice becomes a run of its own.
+pub(crate) fn append<K: Ord, V>(buffer: &mut Buffer<K, V>, slice: SliceThief<(K, V)>) {
+    if slice.len() == 0 {
+        return;
+    }
+    let placement = unwind::catch(|| buffer.placement(&slice.peek_first().0, &slice.peek_last().0))
+        .unwrap_or(Placement::NewRun);
+    buffer.append_placed(slice, placement);
+}
+
+/// Merges a leaf's buffer into its items. Among equal keys the latest
+/// buffered item wins.
+///
+/// After a panic from `Ord`, which is held back, the buffer is left as it
+/// is.
+pub(crate) fn settle_leaf<K: Ord, V>(buffer: &mut Buffer<K, V>, elements: &mut Vec<(K, V)>) {
+    if buffer.is_empty() {
+        return;
+    }
+    let mut steps = Vec::new();
+    if unwind::catch(|| plan_merge(elements, buffer.sorted(), &mut steps, K::cmp)).is_none() {
+        return;
+    }
+    let buffer = buffer.take_sorted();
+    *elements = apply_merge(take(elements), buffer, &steps, unwind::drop_caught);
+}
+
+/// A piece of a buffer being pushed down, as planned by
+/// [`plan_push_down`].
+enum Part {
+    /// The next `len` items go to `child`.
+    Push { child: usize, len: usize },
+    /// The next item replaces the separator at this index.
+    Replace(usize),
+}
+
+/// Hands the buffer of a node with separators `elements` out to its
+/// children, replacing the values of separators with matching keys.
+/// `append` usually adds the slice it is given with [`append`].
+///
+/// After a panic from `Ord`, which is held back, the buffer is left as it
+/// is.
+pub(crate) fn push_down<K: Ord, V>(
+    buffer: &mut Buffer<K, V>,
+    elements: &mut [(K, V)],
+    mut append: impl FnMut(usize, SliceThief<(K, V)>),
+) {
+    if buffer.is_empty() {
+        return;
+    }
+    let mut parts = Vec::new();
+    if unwind::catch(|| plan_push_down(buffer.sorted(), elements, &mut parts)).is_none() {
+        return;
+    }
+    let mut items = buffer.take_sorted();
+    let mut slicer = VecSlicer::new(&mut items);
+    for part in parts {
+        match part {
+            Part::Push { child, len } => {
+                slicer.advance(len);
+                append(child, slicer.slice());
+            }
+            Part::Replace(separator) => {
+                unwind::drop_caught(replace(&mut elements[separator], slicer.take()));
+            }
+        }
+    }
+    drop(slicer);
+    buffer.recycle(items);
+}
+
+/// Works out where each of the sorted `items` goes when they are pushed
+/// down past the separators `elements`, without moving anything.
+fn plan_push_down<K: Ord, V>(items: &[(K, V)], elements: &[(K, V)], parts: &mut Vec<Part>) {
+    let mut child = 0;
+    let mut start = 0;
+    let mut i = 0;
+    while i < items.len() && child < elements.len() {
+        match items[i].0.cmp(&elements[child].0) {
+            Ordering::Less => i += 1,
+            Ordering::Equal => {
+                if i > start {
+                    parts.push(Part::Push {
+                        child,
+                        len: i - start,
+                    });
+                }
+                parts.push(Part::Replace(child));
+                i += 1;
+                start = i;
+            }
+            Ordering::Greater => {
+                if i > start {
+                    parts.push(Part::Push {
+                        child,
+                        len: i - start,
+                    });
+                }
+                start = i;
+                child += 1;
+            }
+        }
+    }
+    if items.len() > start {
+        parts.push(Part::Push {
+            child,
+            len: items.len() - start,
+        });
+    }
+}
+
+/// Settles the children at `indices` of a node, in increasing order, with
+/// `settle`, which returns the pieces that each child split into. The
+/// pieces are taken in right after the child they came from.
+pub(crate) fn settle_children<T, C, E>(
+    elements: &mut Vec<T>,
+    children: &mut Vec<C>,
+    indices: impl DoubleEndedIterator<Item = usize>,
+    mut settle: impl FnMut(&mut C) -> Result<Vec<(T, C)>, E>,
+) -> Result<(), E> {
+    // Right to left, so that taking in splits doesn't shift the
+    // children that are yet to be settled.
+    for i in indices.rev() {
+        let splits = settle(&mut children[i])?;
+        if !splits.is_empty() {
+            let (new_elements, new_children): (Vec<_>, Vec<_>) = splits.into_iter().unzip();
+            elements.splice(i..i, new_elements);
+            children.splice(i + 1..i + 1, new_children);
+        }
+    }
+    Ok(())
+}
+
+/// Where the next items of a merge come from.
+#[derive(Clone, Copy)]
+pub(crate) enum Step {
+    /// The next `n` elements are kept.
+    Keep(usize),
+    /// The next `n` buffered items go in.
+    Insert(usize),
+    /// The next buffered item takes the place of the next element.
+    Replace,
+    /// The next buffered item is superseded by a later one.
+    Skip,
+}
+
+/// Works out how to merge the sorted `buffer` into the sorted `elements`,
+/// without moving anything, into `steps`. The elements left over once the
+/// buffer runs out are kept.
+///
+/// All the comparisons of a merge happen here, so that a panicking `Ord`
+/// can't leave items half moved.
+pub(crate) fn plan_merge<K, V>(
+    elements: &[(K, V)],
+    buffer: &[(K, V)],
+    steps: &mut Vec<Step>,
+    mut cmp: impl FnMut(&K, &K) -> Ordering,
+) {
+    steps.clear();
+    let mut element = 0;
+    for (i, (key, _)) in buffer.iter().enumerate() {
+        if buffer
+            .get(i + 1)
+            .is_some_and(|(next, _)| cmp(key, next).is_eq())
+        {
+            steps.push(Step::Skip);
+            continue;
+        }
+        // A few items are cheaper to place by binary search than by
+        // walking the elements.
+        let found = if buffer.len() <= 2 {
+            elements[element..].binary_search_by(|(k, _)| cmp(k, key))
+        } else {
+            let rest = &elements[element..];
+            let mut j = 0;
+            loop {
+                match rest.get(j).map(|(k, _)| cmp(k, key)) {
+                    Some(Ordering::Less) => j += 1,
+                    Some(Ordering::Equal) => break Ok(j),
+                    _ => break Err(j),
+                }
+            }
+        };
+        let (skipped, step) = match found {
+            Ok(j) => (j, Step::Replace),
+            Err(j) => (j, Step::Insert(1)),
+        };
+        if skipped != 0 {
+            steps.push(Step::Keep(skipped));
+        }
+        element += skipped + usize::from(matches!(step, Step::Replace));
+        match (steps.last_mut(), step) {
+            (Some(Step::Insert(n)), Step::Insert(_)) => *n += 1,
+            _ => steps.push(step),
+        }
+    }
+}
+
+/// Carries out a merge planned by [`plan_merge`], handing the items that
+/// lose to `superseded`. Nothing is compared.
+pub(crate) fn apply_merge<T>(
+    elements: impl IntoIterator<Item = T>,
+    buffer: impl IntoIterator<Item = T>,
+    steps: &[Step],
+    mut superseded: impl FnMut(T),
+) -> Vec<T> {
+    let mut elements = elements.into_iter();
+    let mut buffer = buffer.into_iter();
+    let mut result = Vec::with_capacity(elements.size_hint().0 + steps.len());
+    for step in steps {
+        match step {
+            Step::Keep(n) => result.extend(elements.by_ref().take(*n)),
+            Step::Insert(n) => result.extend(buffer.by_ref().take(*n)),
+            Step::Replace => {
+                superseded(elements.next().unwrap());
+                result.push(buffer.next().unwrap());
+            }
+            Step::Skip => superseded(buffer.next().unwrap()),
+        }
+    }
+    result.extend(elements);
+    result
+}
+
+/// Splits the items of an overflowing node into pieces of about half
+/// capacity, leaving the first piece in place. Returns the other pieces,
+/// each with the separator that goes before it.
+///
+/// `children` is empty for leaves, and otherwise holds one more child
+/// than `elements`.
+#[allow(clippy::type_complexity)]
+pub(crate) fn split<T, C>(
+    elements: &mut Vec<T>,
+    children: &mut Vec<C>,
+) -> Vec<(T, Vec<T>, Vec<C>)> {
+    if elements.len() <= B {
+        return Vec::new();
+    }
+    // As many pieces as possible without any of them dropping below half
+    // capacity, with the items spread evenly between them.
+    let pieces = (elements.len() / (B / 2 + 1)).max(2);
+    let total = elements.len() - (pieces - 1);
+    let piece_len = |piece: usize| total / pieces + usize::from(piece < total % pieces);
+
+    let is_leaf = children.is_empty();
+    let mut rest = elements.split_off(piece_len(0)).into_iter();
+    let mut rest_children = if is_leaf {
+        Vec::new()
+    } else {
+        children.split_off(piece_len(0) + 1)
+    }
+    .into_iter();
+
+    (1..pieces)
+        .map(|piece| {
+            let separator = rest.next().unwrap();
+            let piece: Vec<_> = rest.by_ref().take(piece_len(piece)).collect();
+            let piece_children = if is_leaf {
+                Vec::new()
+            } else {
+                rest_children.by_ref().take(piece.len() + 1).collect()
+            };
+            (separator, piece, piece_children)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        cell::Cell,
+        cmp::Ordering,
+        collections::BTreeMap,
+        panic::{AssertUnwindSafe, catch_unwind},
+    };
+
+    use rand::seq::SliceRandom;
+
+    use super::{settle_leaf, split};
+    use crate::{B, buffer::Buffer};
+
+    thread_local! {
+        /// Comparisons of [`Fused`] keys left on this thread until one panics.
+        static FUSE: Cell<usize> = const { Cell::new(usize::MAX) };
+    }
+
+    /// A key whose comparisons panic once the fuse on their thread runs out.
+    #[derive(Clone, PartialEq, Eq, Debug)]
+    pub(crate) struct Fused(pub(crate) usize);
+
+    impl Ord for Fused {
+        fn cmp(&self, other: &Self) -> Ordering {
+            let fuse = FUSE.get();
+            if fuse == 0 {
+                panic!("fuse blown");
+            }
+            FUSE.set(fuse - 1);
+            self.0.cmp(&other.0)
+        }
+    }
+
+    impl PartialOrd for Fused {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    /// Inserts rounds of keys into a map, and after each round settles it
+    /// with a fuse that blows part way. `settle` looks up the key it is
+    /// given, or flushes the map if there is none. Checks that `len` counts
+    /// every insert and that no item is lost.
+    pub(crate) fn check_panicking_ord<M>(
+        map: &mut M,
+        mut insert: impl FnMut(&mut M, Fused, usize),
+        mut settle: impl FnMut(&mut M, Option<&Fused>),
+        mut get: impl FnMut(&mut M, &Fused) -> Option<usize>,
+        len: impl Fn(&M) -> usize,
+    ) {
+        let mut expected = BTreeMap::new();
+        let mut inserted = 0;
+        let mut blown = 0;
+        for (round, fuse) in [1, 7, 100, 1000, 5000, 20000, 80000]
+            .into_iter()
+            .enumerate()
+        {
+            let mut keys: Vec<_> = (0..B * 20).map(|i| (i * 7 + round) % (B * 30)).collect();
+            keys.shuffle(&mut rand::rng());
+            // An older value for one of the keys, which a merge cut short
+            // by the panic must not move past the newer one.
+            insert(map, Fused(keys[B]), round + 100);
+            for &key in &keys {
+                insert(map, Fused(key), round);
+                expected.insert(key, round);
+            }
+            inserted += keys.len() + 1;
+
+            let probe = Fused(keys[0]);
+            FUSE.set(fuse);
+            let result = catch_unwind(AssertUnwindSafe(|| {
+                settle(map, (round % 2 == 0).then_some(&probe));
+            }));
+            FUSE.set(usize::MAX);
+            blown += usize::from(result.is_err());
+            assert_eq!(len(map), inserted);
+        }
+        assert!(blown > 0);
+
+        settle(map, None);
+        for (&key, &value) in &expected {
+            assert_eq!(get(map, &Fused(key)), Some(value));
+        }
+    }
+
+    #[test]
+    fn merge_later_wins() {
+        let mut elements = vec![(1, 0), (3, 0), (5, 0)];
+        let mut buffer = Buffer::default();
+        for (key, value) in [(6, 1), (3, 1), (0, 1), (3, 2), (6, 2)] {
+            buffer.push(key, value);
+        }
+        settle_leaf(&mut buffer, &mut elements);
+        assert!(buffer.is_empty());
+        assert_eq!(elements, [(0, 1), (1, 0), (3, 2), (5, 0), (6, 2)]);
+        buffer.push(7, 0);
+        buffer.push(3, 3);
+        settle_leaf(&mut buffer, &mut elements);
+        assert_eq!(elements, [(0, 1), (1, 0), (3, 3), (5, 0), (6, 2), (7, 0)]);
+    }
+
+    #[test]
+    fn split_sizes() {
+        for len in [B + 1, B * 2, B * 5 + 3] {
+            let mut elements: Vec<usize> = (0..len).collect();
+            let mut children: Vec<usize> = (0..=len).collect();
+            let rest = split(&mut elements, &mut children);
+
+            assert_eq!(children.len(), elements.len() + 1);
+            assert!((B / 2..=B).contains(&elements.len()));
+            let mut count = elements.len();
+            for (_, piece, piece_children) in &rest {
+                assert!((B / 2..=B).contains(&piece.len()));
+                assert_eq!(piece_children.len(), piece.len() + 1);
+                count += piece.len() + 1;
+            }
+            assert_eq!(count, len);
+        }
+    }
+}
@@ -0,0 +1,445 @@
+use std::{
+    iter::once,
+    ops::{Bound, RangeBounds},
+    thread,
+};
+
+use crate::{
+    Array, Branch, Flush, Map, Motion, Node, Visitor,
+    unwind::{self, Guarded},
+};
+
+/// Progress of the incremental flush driven by [`Map::flush_step`].
+pub(crate) enum FlushState<K> {
+    /// Buffers may hold items, and no pass is underway.
+    Dirty,
+    /// A pass is underway.
+    InProgress(FlushCursor<K>),
+    /// Every buffer is empty.
+    Flushed,
+}
+
+pub(crate) struct FlushCursor<K> {
+    /// The last key of the last leaf settled in the current pass.
+    /// Everything at or before it has already been flushed once.
+    settled_through: Option<K>,
+    /// Whether items were added to the map since the current pass began.
+    /// They may have landed behind the cursor, so the pass must be repeated.
+    stale: bool,
+}
+
+struct FlushStep<K> {
+    cursor: Option<K>,
+    budget: usize,
+    spent: usize,
+}
+
+impl<K: Ord + Clone, V> Visitor<K, V> for FlushStep<K> {
+    #[inline]
+    fn visit_internal(&mut self, array: &mut [Branch<K, V>], temporary: bool) -> Motion {
+        if temporary {
+            // The children of a temporary node have empty buffers, but are
+            // visited anyway so that the cursor moves past their keys.
+            return Motion::VisitAll;
+        }
+        let start = match &self.cursor {
+            None => 0,
+            Some(cursor) => match array.binary_search_by(|b| b.key.cmp(cursor)) {
+                Ok(i) => i + 1,
+                Err(i) => i,
+            },
+        };
+        Motion::VisitRange(start..array.len() + 1)
+    }
+
+    #[inline]
+    fn visit_leaf(&mut self, array: &mut [(K, V)]) {
+        if let Some((last, _)) = array.last()
+            && self.cursor.as_ref().is_none_or(|cursor| last > cursor)
+        {
+            self.spent += 1;
+            self.cursor = Some(last.clone());
+        }
+    }
+
+    #[inline]
+    fn visit_buffer(&mut self, len: usize) {
+        self.spent += len;
+    }
+
+    #[inline]
+    fn exhausted(&self) -> bool {
+        self.spent >= self.budget
+    }
+}
+
+struct FlushRange<'a, K> {
+    start: Bound<&'a K>,
+    end: Bound<&'a K>,
+}
+
+impl<K: Ord, V> Visitor<K, V> for FlushRange<'_, K> {
+    #[inline]
+    fn visit_internal(&mut self, array: &mut [Branch<K, V>], temporary: bool) -> Motion {
+        if temporary {
+            return Motion::Finish;
+        }
+        // Children only hold keys strictly between their neighboring separators,
+        // so whether a bound is inclusive doesn't change which children overlap.
+        let first = match self.start {
+            Bound::Unbounded => 0,
+            Bound::Included(key) | Bound::Excluded(key) => {
+                match array.binary_search_by(|b| b.key.cmp(key)) {
+                    Ok(i) => i + 1,
+                    Err(i) => i,
+                }
+            }
+        };
+        let last = match self.end {
+            Bound::Unbounded => array.len(),
+            Bound::Included(key) | Bound::Excluded(key) => {
+                match array.binary_search_by(|b| b.key.cmp(key)) {
+                    Ok(i) | Err(i) => i,
+                }
+            }
+        };
+        if first <= last {
+            Motion::VisitRange(first..last + 1)
+        } else {
+            Motion::Finish
+        }
+    }
+
+    #[inline]
+    fn visit_leaf(&mut self, _array: &mut [(K, V)]) {}
+}
+
+impl<K: Ord, V> Map<K, V> {
+    /// Processes the buffers of every node whose keys overlap `range`.
+    ///
+    /// Afterwards, queries within the range don't need to push anything down.
+    /// Subtrees entirely outside the range are left untouched, apart from
+    /// receiving the items pushed down from their ancestors on the way.
+    pub fn flush_range(&self, range: impl RangeBounds<K>) {
+        self.accept_visitor(&mut FlushRange {
+            start: range.start_bound(),
+            end: range.end_bound(),
+        });
+    }
+
+    /// Does a bounded amount of the work of [`flush`](Map::flush), picking up
+    /// where the previous call left off.
+    ///
+    /// Each call settles buffered items and leaves until roughly `budget`
+    /// units of work have been done, where moving one buffered item or
+    /// settling one leaf costs one unit. A buffer is always processed as
+    /// a whole, so a single call can overshoot the budget by the size of
+    /// the last buffer it touched. A budget of 0 is treated as 1, so that
+    /// every call makes progress.
+    ///
+    /// Returns whether work remains. Inserts and queries may be freely
+    /// interleaved with calls to this method; items inserted during a pass
+    /// cause another pass to be started once the current one completes.
+    pub fn flush_step(&self, budget: usize) -> bool
+    where
+        K: Clone,
+    {
+        let mut progress = match self.flush_state.replace(FlushState::Dirty) {
+            FlushState::Flushed => {
+                *self.flush_state.borrow_mut() = FlushState::Flushed;
+                return false;
+            }
+            FlushState::Dirty => FlushCursor {
+                settled_through: None,
+                stale: false,
+            },
+            FlushState::InProgress(progress) => progress,
+        };
+        let mut visitor = FlushStep {
+            cursor: progress.settled_through.take(),
+            budget: budget.max(1),
+            spent: 0,
+        };
+        self.accept_visitor(&mut visitor);
+
+        if visitor.spent >= visitor.budget {
+            progress.settled_through = visitor.cursor;
+            *self.flush_state.borrow_mut() = FlushState::InProgress(progress);
+            true
+        } else if progress.stale {
+            true
+        } else {
+            *self.flush_state.borrow_mut() = FlushState::Flushed;
+            false
+        }
+    }
+
+    /// Like [`flush`](Map::flush), but once the root's buffer has been pushed
+    /// down, the root's subtrees are flushed on separate threads.
+    ///
+    /// The subtrees are spread over at most
+    /// [`available_parallelism`](thread::available_parallelism) threads.
+    /// With an [`Observer`](crate::Observer) installed, this is the same as
+    /// [`flush`](Map::flush), so that the observer sees events in order.
+    pub fn flush_parallel(&mut self)
+    where
+        K: Send,
+        V: Send,
+    {
+        self.unbox();
+        if self.observer.get_mut().is_some() {
+            self.flush();
+            return;
+        }
+
+        let root = self.root.get_mut();
+        if let Array::Internal(internal) = &root.array {
+            root.push_down_buffer(internal, &mut Guarded(&mut Flush));
+        }
+        let new_branches = match &mut root.array {
+            Array::Leaf(_) => root.accept_visitor(&mut Guarded(&mut Flush)),
+            Array::Internal(internal) => {
+                let mut children: Vec<&mut Node<K, V>> = once(&mut *internal.first_child)
+                    .chain(
+                        internal
+                            .elements
+                            .get_mut()
+                            .iter_mut()
+                            .map(|b| &mut *b.child),
+                    )
+                    .collect();
+                let threads = thread::available_parallelism().map_or(1, |n| n.get());
+                let per_thread = children.len().div_ceil(threads);
+
+                // Each thread returns the new branches of its subtrees along
+                // with the index of the child they were split off.
+                let splits: Vec<_> = thread::scope(|s| {
+                    let handles: Vec<_> = children
+                        .chunks_mut(per_thread)
+                        .enumerate()
+                        .map(|(chunk_index, chunk)| {
+                            s.spawn(move || {
+                                let mut splits = vec![];
+                                for (i, child) in chunk.iter().enumerate() {
+                                    let new_branches =
+                                        child.accept_visitor(&mut Guarded(&mut Flush));
+                                    splits.push((chunk_index * per_thread + i, new_branches));
+                                }
+                                // Counts and held back panics are kept per
+                                // thread, so they are handed back along with
+                                // the branches.
+                                #[cfg(feature = "metrics")]
+                                let metrics = crate::metrics::take();
+                                #[cfg(not(feature = "metrics"))]
+                                let metrics = ();
+                                (splits, metrics, unwind::take())
+                            })
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .flat_map(|handle| {
+                            let (splits, _metrics, caught) = handle.join().unwrap();
+                            #[cfg(feature = "metrics")]
+                            crate::metrics::add(|metrics| *metrics += _metrics);
+                            if let Some(payload) = caught {
+                                unwind::hold(payload);
+                            }
+                            splits
+                        })
+                        .collect()
+                });
+                internal.insert_branches(splits, &mut Guarded(&mut Flush))
+            }
+        };
+        root.grow(new_branches, &mut Guarded(&mut Flush));
+        unwind::resume();
+        *self.flush_state.get_mut() = FlushState::Flushed;
+        #[cfg(feature = "metrics")]
+        self.collect_metrics(false);
+    }
+
+    pub(crate) fn mark_dirty(&mut self) {
+        let state = self.flush_state.get_mut();
+        match state {
+            FlushState::InProgress(progress) => progress.stale = true,
+            _ => *state = FlushState::Dirty,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ops::Bound, rc::Rc, sync::Arc};
+
+    use crate::{B, Map};
+
+    #[test]
+    fn flush_parallel() {
+        let mut map = Map::new();
+        let max = B * B * 3;
+        for i in 0..max {
+            map.insert(i * 2, Arc::new(i));
+        }
+        map.flush_parallel();
+
+        let x = Arc::new(0);
+        for i in 0..max {
+            map.insert(i * 2 + 1, Arc::new(i));
+            map.insert(i * 2, x.clone());
+        }
+        map.flush_parallel();
+        assert_eq!(Arc::strong_count(&x), max + 1);
+        for i in 0..max {
+            assert_eq!(map.get(&(i * 2)), Some(&x));
+            assert_eq!(**map.get(&(i * 2 + 1)).unwrap(), i);
+        }
+        assert!(!map.flush_step(1));
+    }
+
+    #[test]
+    fn flush_parallel_small() {
+        let mut map = Map::new();
+        map.flush_parallel();
+        map.insert(1, 1);
+        map.insert(1, 2);
+        map.flush_parallel();
+        assert_eq!(map.get(&1), Some(&2));
+    }
+
+    #[test]
+    fn flush_step_clears_duplicates() {
+        let mut map = Map::new();
+        let max = B * B * 3;
+        for i in 0..max {
+            map.insert(i, Rc::new(i));
+        }
+        map.flush();
+
+        let x = Rc::new(0);
+        for _ in 0..100 {
+            map.insert(0, x.clone());
+            map.insert(max - 1, x.clone());
+        }
+        let mut steps = 0;
+        while map.flush_step(B) {
+            steps += 1;
+        }
+        assert!(steps > 1);
+        assert_eq!(Rc::strong_count(&x), 3);
+        assert_eq!(**map.get(&(max - 1)).unwrap(), 0);
+    }
+
+    #[test]
+    fn flush_step_zero_budget() {
+        let mut map = Map::new();
+        let max = B * B * 3;
+        for i in 0..max {
+            map.insert(i, i);
+        }
+        map.flush();
+        for i in 0..max {
+            map.insert(i, i + 1);
+        }
+        let mut steps = 0;
+        while map.flush_step(0) {
+            steps += 1;
+            assert!(steps <= max * 2);
+        }
+        assert_eq!(map.stats().buffered, 0);
+        assert_eq!(map.get(&(max - 1)), Some(&max));
+    }
+
+    #[test]
+    fn flush_range_only_touches_range() {
+        let mut map = Map::new();
+        let max = B * B * 3;
+        for i in 0..max {
+            map.insert(i, Rc::new(i));
+        }
+        map.flush();
+
+        let x = Rc::new(0);
+        let y = Rc::new(1);
+        for _ in 0..100 {
+            map.insert(B, x.clone());
+            map.insert(max - B, y.clone());
+        }
+        map.flush_range(..B * 2);
+        assert_eq!(Rc::strong_count(&x), 2);
+        assert_eq!(Rc::strong_count(&y), 101);
+
+        map.flush_range(max - B..=max - B);
+        assert_eq!(Rc::strong_count(&y), 2);
+        assert_eq!(**map.get(&B).unwrap(), 0);
+        assert_eq!(**map.get(&(max - B)).unwrap(), 1);
+    }
+
+    #[test]
+    fn flush_range_empty_range() {
+        let mut map = Map::new();
+        for i in 0..B * 3 {
+            map.insert(i, i);
+        }
+        map.flush();
+        map.insert(1, 0);
+        map.flush_range((Bound::Included(5), Bound::Excluded(2)));
+        map.flush_range(B * 10..);
+        assert_eq!(map.get(&1), Some(&0));
+    }
+
+    #[test]
+    fn flush_step_on_empty() {
+        let map: Map<usize, usize> = Map::new();
+        assert!(!map.flush_step(1));
+    }
+
+    #[test]
+    fn flush_step_interleaved() {
+        let mut map = Map::new();
+        let max = B * B * 3;
+        for i in (0..max).step_by(2) {
+            map.insert(i, i);
+        }
+        map.flush();
+
+        let mut i = 1;
+        while map.flush_step(10) {
+            if i < max {
+                map.insert(i, i);
+                assert_eq!(map.get(&(i - 1)), Some(&(i - 1)));
+                i += 2;
+            }
+        }
+        for j in (1..i).step_by(2) {
+            map.insert(j + 1, j + 1);
+        }
+        while map.flush_step(B) {}
+        for j in 0..i {
+            assert_eq!(map.get(&j), Some(&j));
+        }
+    }
+
+    #[test]
+    fn flush_step_restarts_when_stale() {
+        let mut map = Map::new();
+        for i in 0..B * B {
+            map.insert(i, i);
+        }
+        map.flush();
+
+        for i in 0..B * B {
+            map.insert(i, i + 1);
+        }
+        assert!(map.flush_step(B));
+        map.insert(0, 0);
+        while map.flush_step(B) {}
+
+        // Everything is settled, so nothing is left for a new pass to do.
+        assert!(!map.flush_step(1));
+        map.insert(1, 2);
+        assert!(map.flush_step(1));
+        assert_eq!(map.get(&0), Some(&0));
+        assert_eq!(map.get(&1), Some(&2));
+    }
+}
@@ -0,0 +1,437 @@
+use std::{
+    cmp::Ordering,
+    io::{self, Write},
+    marker::PhantomData,
+    ops::{Bound, RangeBounds},
+};
+
+use crate::{B, Map, codec::ReadError, get::index_before};
+
+const MAGIC: [u8; 4] = *b"LZFZ";
+const VERSION: u16 = 1;
+const HEADER_LEN: usize = 32;
+
+/// A key or value that always takes the same number of bytes, so that
+/// a [`FrozenMap`] can find it without any parsing.
+pub trait FixedWidth: Sized {
+    const WIDTH: usize;
+
+    /// Writes `self` into exactly [`WIDTH`](FixedWidth::WIDTH) bytes.
+    fn write_bytes(&self, out: &mut [u8]);
+
+    /// Reads a value from exactly [`WIDTH`](FixedWidth::WIDTH) bytes.
+    fn read_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! int_fixed_width {
+    ($($int:ty),*) => {$(
+        impl FixedWidth for $int {
+            const WIDTH: usize = size_of::<$int>();
+
+            fn write_bytes(&self, out: &mut [u8]) {
+                out.copy_from_slice(&self.to_le_bytes());
+            }
+
+            fn read_bytes(bytes: &[u8]) -> Self {
+                <$int>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        }
+    )*};
+}
+
+int_fixed_width!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl<const N: usize> FixedWidth for [u8; N] {
+    const WIDTH: usize = N;
+
+    fn write_bytes(&self, out: &mut [u8]) {
+        out.copy_from_slice(self);
+    }
+
+    fn read_bytes(bytes: &[u8]) -> Self {
+        bytes.try_into().unwrap()
+    }
+}
+
+impl<K: Ord + FixedWidth, V: FixedWidth> Map<K, V> {
+    /// Flushes the map and writes it to `writer` in the layout read by
+    /// [`FrozenMap`].
+    ///
+    /// The items are packed in key order, in leaves of `B` items. They are
+    /// followed by levels of separator keys, where each level holds the
+    /// first key of every group of `B` units of the level below it, except
+    /// the first group.
+    pub fn freeze(&self, writer: &mut impl Write) -> io::Result<()> {
+        self.flush();
+        let root = self.root.borrow();
+        let count = root.count_settled();
+
+        let mut level_lens = vec![];
+        let mut units = count.div_ceil(B);
+        while units > 1 {
+            level_lens.push(units - 1);
+            units = units.div_ceil(B);
+        }
+
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        header[8..12].copy_from_slice(&(K::WIDTH as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(V::WIDTH as u32).to_le_bytes());
+        header[16..24].copy_from_slice(&(count as u64).to_le_bytes());
+        header[24..28].copy_from_slice(&(level_lens.len() as u32).to_le_bytes());
+        writer.write_all(&header)?;
+
+        // The keys that start each leaf after the first, which make up the
+        // lowest level. Higher levels take every `B`th key of the level
+        // below them.
+        let mut separators = vec![];
+        let mut record = vec![0; K::WIDTH + V::WIDTH];
+        let mut index = 0;
+        root.try_for_each_settled(&mut |key, value| {
+            key.write_bytes(&mut record[..K::WIDTH]);
+            value.write_bytes(&mut record[K::WIDTH..]);
+            if index != 0 && index % B == 0 {
+                separators.extend_from_slice(&record[..K::WIDTH]);
+            }
+            index += 1;
+            writer.write_all(&record)
+        })?;
+
+        let mut stride = 1;
+        for len in level_lens {
+            for i in 1..=len {
+                let start = (i * stride - 1) * K::WIDTH;
+                writer.write_all(&separators[start..start + K::WIDTH])?;
+            }
+            stride *= B;
+        }
+        writer.flush()
+    }
+}
+
+/// A read-only map over bytes written by [`Map::freeze`], such as a
+/// memory-mapped file.
+///
+/// Queries search the bytes in place, and only decode the keys they
+/// compare against and the items they return.
+pub struct FrozenMap<'a, K, V> {
+    count: usize,
+    items: &'a [u8],
+    /// Separator levels, lowest first.
+    levels: Vec<&'a [u8]>,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+impl<'a, K: Ord + FixedWidth, V: FixedWidth> FrozenMap<'a, K, V> {
+    /// Checks that `bytes` hold a frozen map with keys and values of the
+    /// right widths.
+    pub fn new(bytes: &'a [u8]) -> Result<Self, ReadError> {
+        let header = bytes.get(..HEADER_LEN).ok_or(ReadError::Truncated)?;
+        if header[..4] != MAGIC {
+            return Err(ReadError::BadMagic);
+        }
+        let version = u16::from_le_bytes(header[4..6].try_into().unwrap());
+        if version != VERSION {
+            return Err(ReadError::UnsupportedVersion(version));
+        }
+        let field = |range: std::ops::Range<usize>| {
+            let mut buf = [0; 8];
+            buf[..range.len()].copy_from_slice(&header[range]);
+            u64::from_le_bytes(buf)
+        };
+        if field(8..12) != K::WIDTH as u64 || field(12..16) != V::WIDTH as u64 {
+            return Err(ReadError::Corrupt("key or value width differs"));
+        }
+        let count = usize::try_from(field(16..24))
+            .map_err(|_| ReadError::Corrupt("item count out of range"))?;
+
+        let mut rest = &bytes[HEADER_LEN..];
+        let mut take = |len: Option<usize>| {
+            let len = len.ok_or(ReadError::Corrupt("item count out of range"))?;
+            if rest.len() < len {
+                return Err(ReadError::Truncated);
+            }
+            let (taken, remaining) = rest.split_at(len);
+            rest = remaining;
+            Ok(taken)
+        };
+        let items = take(count.checked_mul(K::WIDTH + V::WIDTH))?;
+
+        let mut levels = vec![];
+        let mut units = count.div_ceil(B);
+        while units > 1 {
+            levels.push(take((units - 1).checked_mul(K::WIDTH))?);
+            units = units.div_ceil(B);
+        }
+        if field(24..28) != levels.len() as u64 {
+            return Err(ReadError::Corrupt("wrong number of levels"));
+        }
+
+        Ok(FrozenMap {
+            count,
+            items,
+            levels,
+            _marker: PhantomData,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.get_key_value(key).map(|(_, value)| value)
+    }
+
+    pub fn get_key_value(&self, key: &K) -> Option<(K, V)> {
+        let (start, search) = self.search(key);
+        search.ok().map(|i| self.item(start + i))
+    }
+
+    pub fn get_before(&self, key: &K) -> Option<V> {
+        self.get_key_value_before(key).map(|(_, value)| value)
+    }
+
+    pub fn get_before_inc(&self, key: &K) -> Option<V> {
+        self.get_key_value_before_inc(key).map(|(_, value)| value)
+    }
+
+    pub fn get_key_value_before(&self, key: &K) -> Option<(K, V)> {
+        self.before(key, false)
+    }
+
+    pub fn get_key_value_before_inc(&self, key: &K) -> Option<(K, V)> {
+        self.before(key, true)
+    }
+
+    /// Iterates over the items in `range`, in key order.
+    pub fn range(&self, range: impl RangeBounds<K>) -> impl Iterator<Item = (K, V)> + '_ {
+        let start = match range.start_bound() {
+            Bound::Included(key) => self.rank(key, false),
+            Bound::Excluded(key) => self.rank(key, true),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) => self.rank(key, true),
+            Bound::Excluded(key) => self.rank(key, false),
+            Bound::Unbounded => self.count,
+        };
+        (start..end.max(start)).map(|i| self.item(i))
+    }
+
+    fn before(&self, key: &K, inclusive: bool) -> Option<(K, V)> {
+        // Leaves are contiguous, so the item before the start of a leaf is
+        // the last item of the leaf before it.
+        let (start, search) = self.search(key);
+        let search = search.map(|i| start + i).map_err(|i| start + i);
+        index_before(search, inclusive).map(|i| self.item(i))
+    }
+
+    /// The number of items with keys before `key`, or also at it if `inclusive`.
+    fn rank(&self, key: &K, inclusive: bool) -> usize {
+        match self.search(key) {
+            (start, Ok(i)) if inclusive => start + i + 1,
+            (start, Ok(i) | Err(i)) => start + i,
+        }
+    }
+
+    /// Finds the leaf that `key` belongs in, and searches it. Returns the
+    /// index of the leaf's first item, and the result of the search.
+    fn search(&self, key: &K) -> (usize, Result<usize, usize>) {
+        let mut unit = 0;
+        for level in self.levels.iter().rev() {
+            // The children of `unit` are `B` units of the level below, and
+            // the separators between them are the ones for all but the first.
+            let len = level.len() / K::WIDTH;
+            let first = unit * B;
+            let separators = first..((unit + 1) * B - 1).min(len);
+            let search = binary_search(separators.len(), |i| {
+                self.separator(level, first + i).cmp(key)
+            });
+            unit = first
+                + match search {
+                    Ok(i) => i + 1,
+                    Err(i) => i,
+                };
+        }
+
+        let start = unit * B;
+        let len = B.min(self.count - start.min(self.count));
+        (start, binary_search(len, |i| self.key(start + i).cmp(key)))
+    }
+
+    fn separator(&self, level: &[u8], index: usize) -> K {
+        K::read_bytes(&level[index * K::WIDTH..(index + 1) * K::WIDTH])
+    }
+
+    fn record(&self, index: usize) -> &[u8] {
+        let width = K::WIDTH + V::WIDTH;
+        &self.items[index * width..(index + 1) * width]
+    }
+
+    fn key(&self, index: usize) -> K {
+        K::read_bytes(&self.record(index)[..K::WIDTH])
+    }
+
+    fn item(&self, index: usize) -> (K, V) {
+        let record = self.record(index);
+        (
+            K::read_bytes(&record[..K::WIDTH]),
+            V::read_bytes(&record[K::WIDTH..]),
+        )
+    }
+}
+
+/// Like [`slice::binary_search_by`], over the indices `0..len`.
+fn binary_search(len: usize, mut f: impl FnMut(usize) -> Ordering) -> Result<usize, usize> {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match f(mid) {
+            Ordering::Less => low = mid + 1,
+            Ordering::Greater => high = mid,
+            Ordering::Equal => return Ok(mid),
+        }
+    }
+    Err(low)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::FrozenMap;
+    use crate::{B, Map, codec::ReadError};
+
+    fn frozen_bytes(count: u64) -> Vec<u8> {
+        let mut map = Map::new();
+        map.extend_iter((0..count).rev().map(|i| (i * 2, i as u32)));
+        let mut bytes = vec![];
+        map.freeze(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn get_and_before() {
+        for count in [
+            0,
+            1,
+            B as u64,
+            B as u64 + 1,
+            (B * B + 7) as u64,
+            (B * B * 2) as u64,
+        ] {
+            let bytes = frozen_bytes(count);
+            let map = FrozenMap::<u64, u32>::new(&bytes).unwrap();
+            assert_eq!(map.len(), count as usize);
+            for i in 0..count {
+                assert_eq!(map.get(&(i * 2)), Some(i as u32));
+                assert_eq!(map.get(&(i * 2 + 1)), None);
+                assert_eq!(map.get_before(&(i * 2 + 1)), Some(i as u32));
+                assert_eq!(map.get_before_inc(&(i * 2)), Some(i as u32));
+                assert_eq!(
+                    map.get_key_value_before(&(i * 2)),
+                    i.checked_sub(1).map(|j| (j * 2, j as u32))
+                );
+            }
+            assert_eq!(map.get_before(&0), None);
+        }
+    }
+
+    #[test]
+    fn lookups_agree_with_map() {
+        let mut rng = StdRng::seed_from_u64(39);
+        for count in [B - 1, B, B + 1, B * B - 1, B * B, B * B + 1, B * B * 2 + 37] {
+            let mut map = Map::new();
+            for _ in 0..count {
+                map.insert(rng.random_range(0..u64::MAX / 2) * 2, rng.random::<u32>());
+            }
+            let mut bytes = vec![];
+            map.freeze(&mut bytes).unwrap();
+            let frozen = FrozenMap::<u64, u32>::new(&bytes).unwrap();
+            let keys: Vec<_> = frozen.range(..).map(|(key, _)| key).collect();
+
+            // The first and last keys of every leaf, which are also the
+            // separators of the levels above, and the keys around them.
+            let boundaries = (0..keys.len())
+                .filter(|i| i % B == 0 || i % B == B - 1 || i + 1 == keys.len())
+                .map(|i| keys[i]);
+            let random = (0..1000).map(|_| rng.random::<u64>());
+            for key in boundaries.chain(random).chain([0, u64::MAX]) {
+                for probe in [key.saturating_sub(1), key, key.saturating_add(1)] {
+                    let owned = |item: Option<(&u64, &u32)>| item.map(|(&k, &v)| (k, v));
+                    assert_eq!(frozen.get(&probe), map.get(&probe).copied());
+                    assert_eq!(
+                        frozen.get_key_value(&probe),
+                        owned(map.get_key_value(&probe))
+                    );
+                    assert_eq!(frozen.get_before(&probe), map.get_before(&probe).copied());
+                    assert_eq!(
+                        frozen.get_before_inc(&probe),
+                        map.get_before_inc(&probe).copied()
+                    );
+                    assert_eq!(
+                        frozen.get_key_value_before(&probe),
+                        owned(map.get_key_value_before(&probe))
+                    );
+                    assert_eq!(
+                        frozen.get_key_value_before_inc(&probe),
+                        owned(map.get_key_value_before_inc(&probe))
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn range() {
+        let bytes = frozen_bytes((B * 3) as u64);
+        let map = FrozenMap::<u64, u32>::new(&bytes).unwrap();
+        let keys: Vec<_> = map.range(10..=20).map(|(k, _)| k).collect();
+        assert_eq!(keys, [10, 12, 14, 16, 18, 20]);
+        assert_eq!(map.range(11..20).count(), 4);
+        assert_eq!(map.range(..).count(), B * 3);
+        assert_eq!(
+            map.range((Bound::Excluded(20), Bound::Excluded(20)))
+                .count(),
+            0
+        );
+        assert_eq!(map.range((B * 6) as u64..).count(), 0);
+    }
+
+    #[test]
+    fn bad_bytes() {
+        let bytes = frozen_bytes(B as u64 * 2);
+        let err = |bytes: &[u8]| FrozenMap::<u64, u32>::new(bytes).err().unwrap();
+        assert!(matches!(
+            err(&bytes[..bytes.len() - 1]),
+            ReadError::Truncated
+        ));
+        assert!(matches!(err(b"nope"), ReadError::Truncated));
+        assert!(matches!(
+            FrozenMap::<u32, u32>::new(&bytes).err().unwrap(),
+            ReadError::Corrupt(_)
+        ));
+    }
+
+    #[cfg(all(feature = "mmap", unix))]
+    #[test]
+    fn mmap() {
+        use crate::Mmap;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("frozen");
+        std::fs::write(&path, frozen_bytes((B * B) as u64)).unwrap();
+
+        let mmap = unsafe { Mmap::open(&path) }.unwrap();
+        let map = FrozenMap::<u64, u32>::new(&mmap).unwrap();
+        assert_eq!(map.get(&100), Some(50));
+    }
+}
@@ -1,16 +1,29 @@
+use std::ops::Index;
+
 use crate::{Branch, Map, Motion, Visitor};
 
 struct GetVisitor<'a, K, V> {
     key: &'a K,
+    /// Whether the result is handed out through `&self`, so that later
+    /// lookups may move the branch it is in. Through `&mut self`, only a
+    /// branch in a temporary node moves while the result is alive.
+    shared: bool,
     result: Option<*mut V>,
+    /// Whether the result had to be boxed.
+    boxed: bool,
 }
 
 impl<K: Ord, V> Visitor<K, V> for GetVisitor<'_, K, V> {
     #[inline]
-    fn visit_internal(&mut self, array: &mut [Branch<K, V>], _temporary: bool) -> Motion {
-        match array.binary_search_by(|b| b.key.cmp(self.key)) {
+    fn visit_internal(&mut self, array: &mut [Branch<K, V>], temporary: bool) -> Motion {
+        match search(array, self.key, |b| &*b.key) {
             Ok(i) => {
-                self.result = Some(array[i].value.boxify());
+                let value = &mut array[i].value;
+                self.boxed = self.shared || temporary;
+                self.result = Some(match self.boxed {
+                    true => value.boxify(),
+                    false => &mut **value,
+                });
                 Motion::Finish
             }
             Err(i) => Motion::VisitChild(i),
@@ -19,23 +32,61 @@ impl<K: Ord, V> Visitor<K, V> for GetVisitor<'_, K, V> {
 
     #[inline]
     fn visit_leaf(&mut self, array: &mut [(K, V)]) {
-        if let Ok(i) = array.binary_search_by(|(k, _)| k.cmp(self.key)) {
+        if let Ok(i) = search(array, self.key, |(k, _)| k) {
             self.result = Some(&mut array[i].1);
         }
     }
+
+    #[inline]
+    fn boxed(&self) -> bool {
+        self.boxed
+    }
+}
+
+/// Like [`GetVisitor`], but only finds out whether the key is there, so
+/// it never has to box a branch's value.
+struct ContainsKeyVisitor<'a, K> {
+    key: &'a K,
+    found: bool,
+}
+
+impl<K: Ord, V> Visitor<K, V> for ContainsKeyVisitor<'_, K> {
+    #[inline]
+    fn visit_internal(&mut self, array: &mut [Branch<K, V>], _temporary: bool) -> Motion {
+        match search(array, self.key, |b| &*b.key) {
+            Ok(_) => {
+                self.found = true;
+                Motion::Finish
+            }
+            Err(i) => Motion::VisitChild(i),
+        }
+    }
+
+    #[inline]
+    fn visit_leaf(&mut self, array: &mut [(K, V)]) {
+        self.found = search(array, self.key, |(k, _)| k).is_ok();
+    }
 }
 
+/// Like [`GetVisitor`], but for the key as well.
 struct GetKeyValueVisitor<'a, K, V> {
     key: &'a K,
+    shared: bool,
     result: Option<(*const K, *mut V)>,
+    boxed: bool,
 }
 
-impl<K: Ord + Clone, V> Visitor<K, V> for GetKeyValueVisitor<'_, K, V> {
+impl<K: Ord, V> Visitor<K, V> for GetKeyValueVisitor<'_, K, V> {
     #[inline]
-    fn visit_internal(&mut self, array: &mut [Branch<K, V>], _temporary: bool) -> Motion {
-        match array.binary_search_by(|b| b.key.cmp(self.key)) {
+    fn visit_internal(&mut self, array: &mut [Branch<K, V>], temporary: bool) -> Motion {
+        match search(array, self.key, |b| &*b.key) {
             Ok(i) => {
-                self.result = Some((array[i].boxify_key(), array[i].value.boxify()));
+                let branch = &mut array[i];
+                self.boxed = self.shared || temporary;
+                self.result = Some(match self.boxed {
+                    true => (branch.key.boxify().cast_const(), branch.value.boxify()),
+                    false => (&*branch.key, &mut *branch.value),
+                });
                 Motion::Finish
             }
             Err(i) => Motion::VisitChild(i),
@@ -44,40 +95,80 @@ impl<K: Ord + Clone, V> Visitor<K, V> for GetKeyValueVisitor<'_, K, V> {
 
     #[inline]
     fn visit_leaf(&mut self, array: &mut [(K, V)]) {
-        if let Ok(i) = array.binary_search_by(|(k, _)| k.cmp(self.key)) {
+        if let Ok(i) = search(array, self.key, |(k, _)| k) {
             self.result = Some((&array[i].0, &mut array[i].1));
         }
     }
+
+    #[inline]
+    fn boxed(&self) -> bool {
+        self.boxed
+    }
 }
 
 impl<K: Ord, V> Map<K, V> {
+    /// Returns the value for `key`.
+    ///
+    /// A value found in an internal node is moved into an allocation of its
+    /// own, so that the reference stays valid while later lookups push
+    /// buffers down past it. The allocation lasts until the map is next
+    /// borrowed mutably, so under a read-only workload each such value
+    /// keeps it. [`Stats::boxed_values`](crate::Stats::boxed_values) counts
+    /// them. The other lookups through `&self` that return references do
+    /// the same.
     pub fn get(&self, key: &K) -> Option<&V> {
-        let mut visitor = GetVisitor { key, result: None };
-        self.accept_visitor(&mut visitor);
+        let mut visitor = GetVisitor {
+            key,
+            shared: true,
+            result: None,
+            boxed: false,
+        };
+        self.lookup(&mut visitor);
+        self.note_boxed(visitor.boxed);
         visitor.result.map(|ptr| unsafe { &*ptr })
     }
 
+    pub fn contains_key(&self, key: &K) -> bool {
+        let mut visitor = ContainsKeyVisitor { key, found: false };
+        self.lookup(&mut visitor);
+        visitor.found
+    }
+
     pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
-        let mut visitor = GetVisitor { key, result: None };
-        self.accept_visitor(&mut visitor);
+        self.unbox();
+        let mut visitor = GetVisitor {
+            key,
+            shared: false,
+            result: None,
+            boxed: false,
+        };
+        self.lookup(&mut visitor);
+        self.note_boxed(visitor.boxed);
         visitor.result.map(|ptr| unsafe { &mut *ptr })
     }
 
-    pub fn get_key_value(&self, key: &K) -> Option<(&K, &V)>
-    where
-        K: Clone,
-    {
-        let mut visitor = GetKeyValueVisitor { key, result: None };
-        self.accept_visitor(&mut visitor);
+    pub fn get_key_value(&self, key: &K) -> Option<(&K, &V)> {
+        let mut visitor = GetKeyValueVisitor {
+            key,
+            shared: true,
+            result: None,
+            boxed: false,
+        };
+        self.lookup(&mut visitor);
+        self.note_boxed(visitor.boxed);
         visitor.result.map(|(key, val)| unsafe { (&*key, &*val) })
     }
 
-    pub fn get_key_value_mut(&mut self, key: &K) -> Option<(&K, &mut V)>
-    where
-        K: Clone,
-    {
-        let mut visitor = GetKeyValueVisitor { key, result: None };
-        self.accept_visitor(&mut visitor);
+    pub fn get_key_value_mut(&mut self, key: &K) -> Option<(&K, &mut V)> {
+        self.unbox();
+        let mut visitor = GetKeyValueVisitor {
+            key,
+            shared: false,
+            result: None,
+            boxed: false,
+        };
+        self.lookup(&mut visitor);
+        self.note_boxed(visitor.boxed);
         visitor
             .result
             .map(|(key, val)| unsafe { (&*key, &mut *val) })
@@ -89,8 +180,10 @@ impl<K: Ord, V> Map<K, V> {
             inclusive: false,
             result: None,
             previous_branch: None,
+            boxed: false,
         };
-        self.accept_visitor(&mut visitor);
+        self.lookup(&mut visitor);
+        self.note_boxed(visitor.boxed);
         visitor.result.map(|ptr| unsafe { &*ptr })
     }
 
@@ -100,52 +193,88 @@ impl<K: Ord, V> Map<K, V> {
             inclusive: true,
             result: None,
             previous_branch: None,
+            boxed: false,
         };
-        self.accept_visitor(&mut visitor);
+        self.lookup(&mut visitor);
+        self.note_boxed(visitor.boxed);
         visitor.result.map(|ptr| unsafe { &*ptr })
     }
 
-    pub fn get_key_value_before(&self, key: &K) -> Option<(&K, &V)>
-    where
-        K: Clone,
-    {
+    pub fn get_key_value_before(&self, key: &K) -> Option<(&K, &V)> {
         let mut visitor = GetKeyValueBeforeVisitor {
             key,
             inclusive: false,
             result: None,
             previous_branch: None,
+            boxed: false,
         };
-        self.accept_visitor(&mut visitor);
+        self.lookup(&mut visitor);
+        self.note_boxed(visitor.boxed);
         visitor.result.map(|(key, val)| unsafe { (&*key, &*val) })
     }
 
-    pub fn get_key_value_before_inc(&self, key: &K) -> Option<(&K, &V)>
-    where
-        K: Clone,
-    {
+    pub fn get_key_value_before_inc(&self, key: &K) -> Option<(&K, &V)> {
         let mut visitor = GetKeyValueBeforeVisitor {
             key,
             inclusive: true,
             result: None,
             previous_branch: None,
+            boxed: false,
         };
-        self.accept_visitor(&mut visitor);
+        self.lookup(&mut visitor);
+        self.note_boxed(visitor.boxed);
         visitor.result.map(|(key, val)| unsafe { (&*key, &*val) })
     }
 }
 
+impl<K: Ord, V> Index<&K> for Map<K, V> {
+    type Output = V;
+
+    /// Returns the value for `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key is not in the map.
+    fn index(&self, key: &K) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+/// Searches the array of a node for `key`.
+#[inline]
+fn search<T, K: Ord>(array: &[T], key: &K, key_of: impl Fn(&T) -> &K) -> Result<usize, usize> {
+    count!(nodes_visited);
+    array.binary_search_by(|item| {
+        count!(comparisons);
+        key_of(item).cmp(key)
+    })
+}
+
+/// The index of the last item before the key that was searched for, or
+/// at it if `inclusive`, given the result of the search. `None` if the
+/// item comes before everything that was searched.
+#[inline]
+pub(crate) fn index_before(search: Result<usize, usize>, inclusive: bool) -> Option<usize> {
+    match search {
+        Ok(i) if inclusive => Some(i),
+        Ok(i) | Err(i) => i.checked_sub(1),
+    }
+}
+
 struct GetValueBeforeVisitor<'a, K, V> {
     key: &'a K,
     inclusive: bool,
     previous_branch: Option<*mut Branch<K, V>>,
     result: Option<*mut V>,
+    boxed: bool,
 }
 
 impl<K: Ord, V> Visitor<K, V> for GetValueBeforeVisitor<'_, K, V> {
     #[inline]
     fn visit_internal(&mut self, array: &mut [Branch<K, V>], _temporary: bool) -> Motion {
-        match array.binary_search_by(|b| b.key.cmp(self.key)) {
+        match search(array, self.key, |b| &*b.key) {
             Ok(i) if self.inclusive => {
+                self.boxed = true;
                 self.result = Some(array[i].value.boxify());
                 Motion::Finish
             }
@@ -160,20 +289,23 @@ impl<K: Ord, V> Visitor<K, V> for GetValueBeforeVisitor<'_, K, V> {
 
     #[inline]
     fn visit_leaf(&mut self, array: &mut [(K, V)]) {
-        match array.binary_search_by(|(k, _)| k.cmp(self.key)) {
-            Ok(i) if self.inclusive => self.result = Some(&mut array[i].1),
-            Ok(i) | Err(i) => {
-                if i != 0 {
-                    self.result = Some(&mut array[i - 1].1);
-                } else {
-                    self.result = self.previous_branch.map(|b| {
-                        let b = unsafe { &mut *b };
-                        b.value.boxify()
-                    })
-                }
+        let search = search(array, self.key, |(k, _)| k);
+        match index_before(search, self.inclusive) {
+            Some(i) => self.result = Some(&mut array[i].1),
+            None => {
+                self.boxed = self.previous_branch.is_some();
+                self.result = self.previous_branch.map(|b| {
+                    let b = unsafe { &mut *b };
+                    b.value.boxify()
+                })
             }
         }
     }
+
+    #[inline]
+    fn boxed(&self) -> bool {
+        self.boxed
+    }
 }
 
 struct GetKeyValueBeforeVisitor<'a, K, V> {
@@ -181,14 +313,16 @@ struct GetKeyValueBeforeVisitor<'a, K, V> {
     inclusive: bool,
     previous_branch: Option<*mut Branch<K, V>>,
     result: Option<(*const K, *mut V)>,
+    boxed: bool,
 }
 
-impl<K: Ord + Clone, V> Visitor<K, V> for GetKeyValueBeforeVisitor<'_, K, V> {
+impl<K: Ord, V> Visitor<K, V> for GetKeyValueBeforeVisitor<'_, K, V> {
     #[inline]
     fn visit_internal(&mut self, array: &mut [Branch<K, V>], _temporary: bool) -> Motion {
-        match array.binary_search_by(|b| b.key.cmp(self.key)) {
+        match search(array, self.key, |b| &*b.key) {
             Ok(i) if self.inclusive => {
-                self.result = Some((array[i].boxify_key(), array[i].value.boxify()));
+                self.boxed = true;
+                self.result = Some((array[i].key.boxify().cast_const(), array[i].value.boxify()));
                 Motion::Finish
             }
             Ok(i) | Err(i) => {
@@ -202,20 +336,23 @@ impl<K: Ord + Clone, V> Visitor<K, V> for GetKeyValueBeforeVisitor<'_, K, V> {
 
     #[inline]
     fn visit_leaf(&mut self, array: &mut [(K, V)]) {
-        match array.binary_search_by(|(k, _)| k.cmp(self.key)) {
-            Ok(i) if self.inclusive => self.result = Some((&mut array[i].0, &mut array[i].1)),
-            Ok(i) | Err(i) => {
-                if i != 0 {
-                    self.result = Some((&mut array[i - 1].0, &mut array[i - 1].1));
-                } else {
-                    self.result = self.previous_branch.map(|b| {
-                        let b = unsafe { &mut *b };
-                        (b.boxify_key(), b.value.boxify())
-                    })
-                }
+        let search = search(array, self.key, |(k, _)| k);
+        match index_before(search, self.inclusive) {
+            Some(i) => self.result = Some((&mut array[i].0, &mut array[i].1)),
+            None => {
+                self.boxed = self.previous_branch.is_some();
+                self.result = self.previous_branch.map(|b| {
+                    let b = unsafe { &mut *b };
+                    (b.key.boxify().cast_const(), b.value.boxify())
+                })
             }
         }
     }
+
+    #[inline]
+    fn boxed(&self) -> bool {
+        self.boxed
+    }
 }
 
 #[cfg(test)]
@@ -361,4 +498,144 @@ mod tests {
         *val = 5;
         assert_eq!(map.get(&1).unwrap(), &5);
     }
+
+    #[test]
+    fn contains_key_without_boxing() {
+        let mut map = Map::new();
+        map.extend_sorted_iter((0..B * B).map(|i| (i * 2, i)));
+        map.insert(1, 1);
+        for i in 0..B * B {
+            assert!(map.contains_key(&(i * 2)));
+            assert_eq!(map.contains_key(&(i * 2 + 1)), i == 0);
+        }
+        assert_eq!(map.stats().boxed_values, 0);
+    }
+
+    #[test]
+    fn index() {
+        let mut map = Map::new();
+        map.insert(1, "asdf");
+        map.insert(3, "zxcv");
+        assert_eq!(map[&1], "asdf");
+        assert_eq!(map[&3], "zxcv");
+    }
+
+    #[test]
+    #[should_panic(expected = "no entry found for key")]
+    fn index_missing() {
+        let mut map = Map::new();
+        map.insert(1, 1);
+        let _ = map[&2];
+    }
+
+    #[test]
+    fn unbox_on_mutable_borrow() {
+        let mut map = Map::new();
+        map.extend_sorted_iter((0..B * B).map(|i| (i, i)));
+        for i in 0..B * B {
+            assert_eq!(map.get_key_value(&i), Some((&i, &i)));
+        }
+        let stats = map.stats();
+        assert!(stats.boxed_values > 0);
+        assert_eq!(stats.boxed_keys, stats.boxed_values);
+
+        map.insert(B * B, 0);
+        let stats = map.stats();
+        assert_eq!((stats.boxed_values, stats.boxed_keys), (0, 0));
+        for i in 0..B * B {
+            assert_eq!(map.get(&i), Some(&i));
+        }
+
+        // Mutable lookups hold the map borrowed, so they needn't box.
+        for i in 0..B * B {
+            *map.get_mut(&i).unwrap() += 1;
+            let (_, value) = map.get_key_value_mut(&i).unwrap();
+            *value += 1;
+        }
+        assert_eq!(map.stats().boxed_values, 0);
+        for i in 0..B * B {
+            assert_eq!(map.get(&i), Some(&(i + 2)));
+        }
+    }
+
+    #[test]
+    fn read_only_lookups_keep_their_boxes() {
+        let mut map = Map::new();
+        map.extend_sorted_iter((0..B * B).map(|i| (i, i)));
+        map.flush();
+        let stats = map.stats();
+        let internal_levels = &stats.levels[..stats.height - 1];
+        let branches: usize = internal_levels.iter().map(|level| level.elements).sum();
+        assert!(branches > 0);
+        for _ in 0..2 {
+            for i in 0..B * B {
+                assert_eq!(map.get(&i), Some(&i));
+                assert_eq!(map.get_before_inc(&i), Some(&i));
+            }
+            // Each separator is boxed once, however often it is looked up.
+            let stats = map.stats();
+            assert_eq!((stats.boxed_values, stats.boxed_keys), (branches, 0));
+        }
+        assert!(map.get_mut(&0).is_some());
+        assert_eq!(map.stats().boxed_values, 0);
+    }
+
+    #[test]
+    fn unbox_only_walks_boxed_paths() {
+        let mut map = Map::new();
+        map.extend_sorted_iter((0..B * B * 4).map(|i| (i, i)));
+        map.flush();
+        let stats = map.stats();
+        assert!(stats.height >= 3);
+        let branches: usize = stats.levels[..stats.height - 1]
+            .iter()
+            .map(|level| level.elements)
+            .sum();
+        let path = B * (stats.height - 1);
+
+        // The smallest separator, found by a lookup boxing its value.
+        let separator = (0..)
+            .find(|i| {
+                map.get(i);
+                map.boxed.get()
+            })
+            .unwrap();
+        let touched = map.root.get_mut().unbox();
+        assert!(touched <= path && touched < branches / 2);
+        assert_eq!(map.stats().boxed_values, 0);
+
+        // The separator right before a leaf, boxed from the leaf.
+        assert_eq!(
+            map.get_key_value_before(&(separator + 1)),
+            Some((&separator, &separator))
+        );
+        assert_eq!(map.stats().boxed_values, 1);
+        let touched = map.root.get_mut().unbox();
+        assert!(touched <= path && touched < branches / 2);
+        assert_eq!(map.stats().boxed_values, 0);
+        assert_eq!(map.root.get_mut().unbox(), 0);
+    }
+
+    #[test]
+    fn key_value_without_clone() {
+        #[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+        struct Key(usize);
+
+        let mut map = Map::new();
+        for i in 0..B * B {
+            map.insert(Key(i), i);
+        }
+        let found: Vec<_> = (0..B * B)
+            .map(|i| map.get_key_value(&Key(i)).unwrap())
+            .collect();
+        for (i, (key, value)) in found.into_iter().enumerate() {
+            assert_eq!((key, value), (&Key(i), &i));
+        }
+        assert_eq!(
+            map.get_key_value_before(&Key(B * B)),
+            Some((&Key(B * B - 1), &(B * B - 1)))
+        );
+        assert_eq!(map.get_key_value_before_inc(&Key(0)), Some((&Key(0), &0)));
+        assert_eq!(map.get_key_value_mut(&Key(1)), Some((&Key(1), &mut 1)));
+    }
 }
@@ -1,25 +1,96 @@
+/// Adds to one of the [`Metrics`] counts for the operation in progress, if
+/// the `metrics` feature is enabled.
+macro_rules! count {
+    ($field:ident) => {
+        count!($field, 1)
+    };
+    ($field:ident, $n:expr) => {
+        #[cfg(feature = "metrics")]
+        crate::metrics::add(|metrics| metrics.$field += $n as u64);
+    };
+}
+
+mod adaptive;
+mod bplus;
+mod buffer;
+mod bulk;
+#[cfg(any(test, feature = "debug-check"))]
+mod check;
+mod codec;
+mod dump;
+mod flat;
+mod flush;
+mod frozen;
 mod get;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(all(feature = "mmap", unix))]
+mod mmap;
+mod observer;
+mod paged;
+mod persistent;
+#[cfg(feature = "serde")]
+mod serde_impl;
+mod stats;
+mod sync;
+mod traits;
+mod unwind;
 mod vec_slicer;
+mod wal;
 
 use std::{
-    cell::{Cell, RefCell, RefMut},
+    cell::{Cell, RefCell},
     cmp::Ordering,
-    collections::VecDeque,
-    iter::Peekable,
-    mem::take,
-    ops::{Deref, DerefMut},
+    mem::{replace, take},
+    ops::{Deref, DerefMut, Range},
 };
 
 use arrayvec::ArrayVec;
 use replace_with::replace_with_or_abort;
 
-use crate::vec_slicer::{SliceThief, VecSlicer};
+#[cfg(any(test, feature = "debug-check"))]
+pub use crate::check::{InvariantError, Violation};
+#[cfg(feature = "metrics")]
+pub use crate::metrics::Metrics;
+#[cfg(all(feature = "mmap", unix))]
+pub use crate::mmap::Mmap;
+pub use crate::{
+    adaptive::InsertStrategy,
+    bplus::BPlusMap,
+    codec::{Codec, ReadError},
+    frozen::{FixedWidth, FrozenMap},
+    observer::Observer,
+    paged::PagedMap,
+    persistent::{PersistentMap, Snapshot},
+    stats::{LevelStats, Stats},
+    sync::SyncMap,
+};
+use crate::{
+    adaptive::InsertVisitor,
+    buffer::{Buffer, Placement},
+    flat::{Step, apply_merge, plan_merge},
+    flush::FlushState,
+    observer::Observed,
+    unwind::Guarded,
+    vec_slicer::{SliceThief, VecSlicer},
+    wal::Wal,
+};
 
 const B: usize = 150;
 
 pub struct Map<K, V> {
     root: RefCell<Node<K, V>>,
     length: usize,
+    flush_state: RefCell<FlushState<K>>,
+    insert_strategy: InsertStrategy,
+    read_bias: Cell<i32>,
+    /// Whether lookups have boxed any branch values or keys since the map
+    /// was last borrowed mutably.
+    boxed: Cell<bool>,
+    wal: Option<Wal<K, V>>,
+    observer: RefCell<Option<Box<dyn Observer<K, V> + Send>>>,
+    #[cfg(feature = "metrics")]
+    metrics: Cell<Metrics>,
 }
 
 impl<K, V> Map<K, V> {
@@ -27,12 +98,37 @@ impl<K, V> Map<K, V> {
         Map {
             root: RefCell::new(Node {
                 buffer: Default::default(),
-                buffer_is_sorted: Cell::new(true),
                 array: Array::Leaf(LeafArray {
                     elements: Default::default(),
                 }),
             }),
             length: 0,
+            flush_state: RefCell::new(FlushState::Flushed),
+            insert_strategy: InsertStrategy::Lazy,
+            read_bias: Cell::new(0),
+            boxed: Cell::new(false),
+            wal: None,
+            observer: RefCell::new(None),
+            #[cfg(feature = "metrics")]
+            metrics: Cell::default(),
+        }
+    }
+}
+
+impl<K, V> Map<K, V> {
+    /// Records that a lookup boxed a branch's value or key, so that the
+    /// reference it handed out stays put while the tree changes under `&self`.
+    fn note_boxed(&self, boxed: bool) {
+        if boxed {
+            self.boxed.set(true);
+        }
+    }
+
+    /// Moves boxed branch values and keys back inline. With `&mut self`, no
+    /// references handed out by lookups can still be alive.
+    fn unbox(&mut self) {
+        if self.boxed.replace(false) {
+            self.root.get_mut().unbox();
         }
     }
 }
@@ -45,11 +141,26 @@ impl<K, V> Default for Map<K, V> {
 
 impl<K: Ord, V> Map<K, V> {
     pub fn insert(&mut self, key: K, value: V) {
-        self.root.borrow_mut().insert(key, value);
+        self.unbox();
+        self.log(&key, &value);
+        self.flush_log();
         self.length += 1;
+        if self.record_write() {
+            self.accept_visitor(&mut InsertVisitor::new(key, value));
+        } else {
+            self.root.borrow_mut().insert(key, value);
+            self.mark_dirty();
+        }
     }
 
     pub fn extend_from_vec(&mut self, vec: &mut Vec<(K, V)>) {
+        self.unbox();
+        for (key, value) in vec.iter() {
+            self.log(key, value);
+        }
+        self.flush_log();
+        self.record_burst();
+        self.mark_dirty();
         self.length += vec.len();
         self.root
             .borrow_mut()
@@ -57,6 +168,13 @@ impl<K: Ord, V> Map<K, V> {
     }
 
     pub fn extend_from_sorted_vec(&mut self, vec: &mut Vec<(K, V)>) {
+        self.unbox();
+        for (key, value) in vec.iter() {
+            self.log(key, value);
+        }
+        self.flush_log();
+        self.record_burst();
+        self.mark_dirty();
         self.length += vec.len();
         self.root
             .borrow_mut()
@@ -81,35 +199,90 @@ impl<K: Ord, V> Map<K, V> {
     /// Recursively processes all buffers in the map.
     pub fn flush(&self) {
         self.accept_visitor(&mut Flush);
+        *self.flush_state.borrow_mut() = FlushState::Flushed;
     }
 
-    fn accept_visitor(&self, visitor: &mut impl Visitor<K, V>) {
-        let mut root = self.root.borrow_mut();
-        let mut new_branches = root.accept_visitor(visitor);
+    /// Runs a visitor on behalf of a query.
+    fn lookup(&self, visitor: &mut impl Visitor<K, V>) {
+        self.record_read();
+        self.traverse(visitor);
+        #[cfg(feature = "metrics")]
+        self.collect_metrics(true);
+    }
 
-        while !new_branches.is_empty() {
-            replace_with_or_abort(&mut *root, |root| {
-                let new_array = InternalArray {
-                    first_child: Box::new(root),
-                    elements: Default::default(),
-                };
-                replace_with_or_abort(&mut new_branches, |mut branches| {
-                    new_array.process_branches(branches.drain(..))
-                });
+    fn accept_visitor(&self, visitor: &mut impl Visitor<K, V>) {
+        self.traverse(visitor);
+        #[cfg(feature = "metrics")]
+        self.collect_metrics(false);
+    }
 
-                Node {
-                    buffer: Default::default(),
-                    buffer_is_sorted: Cell::new(true),
-                    array: Array::Internal(new_array),
-                }
-            });
+    /// Runs `visitor` from the root, adding a level if the root splits.
+    ///
+    /// A panic in the visitor, the observer or the keys' `Ord` is held back
+    /// until the tree is consistent again, then rethrown.
+    fn traverse(&self, visitor: &mut impl Visitor<K, V>) {
+        let mut root = self.root.borrow_mut();
+        match self.observer.borrow_mut().as_deref_mut() {
+            Some(observer) => {
+                let mut visitor = Observed { visitor, observer };
+                let mut visitor = Guarded(&mut visitor);
+                let new_branches = root.accept_visitor(&mut visitor);
+                root.grow(new_branches, &mut visitor);
+            }
+            None => {
+                let mut visitor = Guarded(visitor);
+                let new_branches = root.accept_visitor(&mut visitor);
+                root.grow(new_branches, &mut visitor);
+            }
         }
+        drop(root);
+        unwind::resume();
     }
 }
 
 trait Visitor<K, V> {
     fn visit_internal(&mut self, array: &mut [Branch<K, V>], temporary: bool) -> Motion;
     fn visit_leaf(&mut self, array: &mut [(K, V)]);
+
+    /// Called with the length of a node's buffer right before it is processed.
+    #[inline]
+    fn visit_buffer(&mut self, _len: usize) {}
+
+    /// Checked before each child after the first during [`Motion::VisitRange`].
+    /// Returning true skips the remaining children.
+    #[inline]
+    fn exhausted(&self) -> bool {
+        false
+    }
+
+    /// Whether the visitor has boxed a branch's key or value, to hand out a
+    /// reference to it.
+    #[inline]
+    fn boxed(&self) -> bool {
+        false
+    }
+
+    /// Called on reaching a leaf. An item returned here is added to the back
+    /// of the leaf's buffer, so it is processed as the newest item there.
+    /// Also called for [`Motion::Replace`].
+    #[inline]
+    fn deliver(&mut self) -> Option<(K, V)> {
+        None
+    }
+
+    /// Called with an item that was dropped in favor of a newer item with
+    /// an equal key.
+    #[inline]
+    fn superseded(&mut self, _key: K, _value: V) {}
+
+    /// Called when a node splits off `_new_nodes` siblings.
+    #[inline]
+    fn split(&mut self, _new_nodes: usize) {}
+
+    /// Called when `_len` items of an internal node's buffer are pushed down
+    /// into its children's buffers.
+    #[inline]
+    fn pushed_down(&mut self, _len: usize) {}
 }
 
 impl<K, V> Visitor<K, V> for Box<dyn Visitor<K, V>> {
@@ -120,17 +293,50 @@ impl<K, V> Visitor<K, V> for Box<dyn Visitor<K, V>> {
     fn visit_leaf(&mut self, array: &mut [(K, V)]) {
         (**self).visit_leaf(array);
     }
+
+    fn visit_buffer(&mut self, len: usize) {
+        (**self).visit_buffer(len);
+    }
+
+    fn exhausted(&self) -> bool {
+        (**self).exhausted()
+    }
+
+    fn boxed(&self) -> bool {
+        (**self).boxed()
+    }
+
+    fn deliver(&mut self) -> Option<(K, V)> {
+        (**self).deliver()
+    }
+
+    fn superseded(&mut self, key: K, value: V) {
+        (**self).superseded(key, value);
+    }
+
+    fn split(&mut self, new_nodes: usize) {
+        (**self).split(new_nodes);
+    }
+
+    fn pushed_down(&mut self, len: usize) {
+        (**self).pushed_down(len);
+    }
 }
 
 enum Motion {
     Finish,
     VisitChild(usize),
     VisitAll,
+    /// Visits the children in the given range of indices, in order, until
+    /// the visitor is [exhausted](Visitor::exhausted).
+    VisitRange(Range<usize>),
+    /// Puts the item that the visitor [delivers](Visitor::deliver) in
+    /// place of the separator at the given index, which has the same key.
+    Replace(usize),
 }
 
 struct Node<K, V> {
-    buffer: RefCell<VecDeque<(K, V)>>,
-    buffer_is_sorted: Cell<bool>,
+    buffer: RefCell<Buffer<K, V>>,
     array: Array<K, V>,
 }
 
@@ -138,22 +344,21 @@ impl<K: Ord, V> Node<K, V> {
     fn accept_visitor(&self, visitor: &mut impl Visitor<K, V>) -> Vec<Branch<K, V>> {
         match &self.array {
             Array::Internal(internal) => {
-                let mut buffer = self.buffer.borrow_mut();
-                if !buffer.is_empty() {
-                    replace_with_or_abort(&mut *buffer, |buffer| {
-                        let mut vec = Vec::from(buffer);
-                        if !self.buffer_is_sorted.get() {
-                            vec.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
-                        }
-                        internal.push_down(&mut vec);
-                        VecDeque::from(vec)
-                    });
-                }
+                self.push_down_buffer(internal, visitor);
 
                 let mut elements = internal.elements.borrow_mut();
 
                 match visitor.visit_internal(elements.as_mut_slice(), false) {
-                    Motion::Finish => vec![],
+                    Motion::Finish => {
+                        internal.note_boxed(visitor);
+                        vec![]
+                    }
+                    Motion::Replace(i) => {
+                        if let Some((key, value)) = visitor.deliver() {
+                            elements[i].replace(key, value, visitor);
+                        }
+                        vec![]
+                    }
                     Motion::VisitChild(which) => {
                         let child = if which == 0 {
                             &*internal.first_child
@@ -162,48 +367,68 @@ impl<K: Ord, V> Node<K, V> {
                         };
                         let new_branches = child.accept_visitor(visitor);
                         drop(elements);
-                        internal.process_branches(new_branches.into_iter())
+                        internal.insert_branches([(which, new_branches)], visitor)
                     }
                     Motion::VisitAll => {
-                        let mut new_branches = internal.first_child.accept_visitor(visitor);
-                        for branch in &**elements {
-                            new_branches.extend(branch.child.accept_visitor(visitor));
+                        let mut splits = vec![(0, internal.first_child.accept_visitor(visitor))];
+                        for (i, branch) in elements.iter().enumerate() {
+                            splits.push((i + 1, branch.child.accept_visitor(visitor)));
                         }
-                        new_branches
+                        drop(elements);
+                        internal.insert_branches(splits, visitor)
+                    }
+                    Motion::VisitRange(range) => {
+                        let start = range.start;
+                        let mut splits = vec![];
+                        for which in range {
+                            if which > start && visitor.exhausted() {
+                                break;
+                            }
+                            let child = if which == 0 {
+                                &*internal.first_child
+                            } else {
+                                &*elements[which - 1].child
+                            };
+                            splits.push((which, child.accept_visitor(visitor)));
+                        }
+                        drop(elements);
+                        internal.insert_branches(splits, visitor)
                     }
                 }
             }
             Array::Leaf(leaf) => {
+                if let Some((key, value)) = visitor.deliver() {
+                    unwind::catch(|| self.insert(key, value));
+                }
                 let mut buffer = self.buffer.borrow_mut();
 
                 if !buffer.is_empty() {
-                    if !self.buffer_is_sorted.get() {
-                        buffer
-                            .make_contiguous()
-                            .sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+                    visitor.visit_buffer(buffer.len());
+                    if !buffer.is_sorted() {
+                        count!(sorts);
                     }
-                    let mut new_branches = leaf.process_buffer(buffer.drain(..));
-                    drop(buffer);
-                    if !new_branches.is_empty() {
-                        match visitor.visit_internal(new_branches.as_mut_slice(), true) {
-                            Motion::Finish => {}
-                            Motion::VisitChild(which) => {
-                                let child = if which == 0 {
-                                    self
-                                } else {
-                                    &*new_branches[which - 1].child
-                                };
-                                let should_be_empty = child.accept_visitor(visitor);
-                                debug_assert!(should_be_empty.is_empty());
-                            }
-                            Motion::VisitAll => {
-                                debug_assert!(self.accept_visitor(visitor).is_empty());
-                                for branch in &*new_branches {
-                                    debug_assert!(branch.child.accept_visitor(visitor).is_empty());
-                                }
-                            }
+                    let mut steps = STEPS.take();
+                    let planned = unwind::catch(|| {
+                        let elements = leaf.elements.borrow();
+                        plan_merge(
+                            elements.as_slice(),
+                            buffer.sorted(),
+                            &mut steps,
+                            |k1, k2| {
+                                count!(comparisons);
+                                k1.cmp(k2)
+                            },
+                        )
+                    });
+                    if planned.is_some() {
+                        let vec = buffer.take_sorted();
+                        let mut new_branches = leaf.process_buffer(vec, &steps, visitor);
+                        STEPS.set(steps);
+                        drop(buffer);
+                        if !new_branches.is_empty() {
+                            self.visit_split(&mut new_branches, visitor);
+                            return new_branches;
                         }
-                        return new_branches;
                     }
                 }
                 visitor.visit_leaf(leaf.elements.borrow_mut().as_mut_slice());
@@ -211,6 +436,129 @@ impl<K: Ord, V> Node<K, V> {
             }
         }
     }
+
+    /// Lets the visitor into a leaf that has just split, through a
+    /// temporary internal node holding `new_branches`.
+    fn visit_split(&self, new_branches: &mut [Branch<K, V>], visitor: &mut impl Visitor<K, V>) {
+        match visitor.visit_internal(new_branches, true) {
+            Motion::Finish => {}
+            Motion::Replace(i) => {
+                if let Some((key, value)) = visitor.deliver() {
+                    new_branches[i].replace(key, value, visitor);
+                }
+            }
+            Motion::VisitChild(which) => {
+                let child = if which == 0 {
+                    self
+                } else {
+                    &*new_branches[which - 1].child
+                };
+                let should_be_empty = child.accept_visitor(visitor);
+                debug_assert!(should_be_empty.is_empty());
+            }
+            Motion::VisitAll => {
+                let should_be_empty = self.accept_visitor(visitor);
+                debug_assert!(should_be_empty.is_empty());
+                for branch in &*new_branches {
+                    let should_be_empty = branch.child.accept_visitor(visitor);
+                    debug_assert!(should_be_empty.is_empty());
+                }
+            }
+            Motion::VisitRange(range) => {
+                for which in range {
+                    let child = if which == 0 {
+                        self
+                    } else {
+                        &*new_branches[which - 1].child
+                    };
+                    let should_be_empty = child.accept_visitor(visitor);
+                    debug_assert!(should_be_empty.is_empty());
+                }
+            }
+        }
+    }
+
+    /// Pushes the buffer of this internal node down to its children.
+    ///
+    /// Keys are all compared before any item moves, so if `Ord` panics the
+    /// buffer stays where it is and the panic is held back.
+    fn push_down_buffer(&self, internal: &InternalArray<K, V>, visitor: &mut impl Visitor<K, V>) {
+        let mut buffer = self.buffer.borrow_mut();
+        if buffer.is_empty() {
+            return;
+        }
+        visitor.visit_buffer(buffer.len());
+        if !buffer.is_sorted() {
+            count!(sorts);
+        }
+        let mut segments = SEGMENTS.take();
+        if unwind::catch(|| internal.plan_push_down(buffer.sorted(), &mut segments)).is_none() {
+            return;
+        }
+        let mut vec = buffer.take_sorted();
+        internal.push_down(&mut vec, &segments, visitor);
+        buffer.recycle(vec);
+        SEGMENTS.set(segments);
+    }
+}
+impl<K, V> Node<K, V> {
+    /// Moves the boxed branch values and keys of this subtree back inline,
+    /// skipping the subtrees that lookups haven't boxed anything in.
+    /// Returns how many branches it went through.
+    fn unbox(&mut self) -> usize {
+        let Array::Internal(internal) = &mut self.array else {
+            return 0;
+        };
+        if !internal.boxed.replace(false) {
+            return 0;
+        }
+        let mut touched = internal.first_child.unbox();
+        for branch in internal.elements.get_mut().iter_mut() {
+            branch.key.unbox();
+            branch.value.unbox();
+            touched += 1 + branch.child.unbox();
+        }
+        touched
+    }
+
+    /// Calls `f` on every item of a flushed tree, in key order.
+    fn try_for_each_settled<E>(
+        &self,
+        f: &mut impl FnMut(&K, &V) -> Result<(), E>,
+    ) -> Result<(), E> {
+        debug_assert!(self.buffer.borrow().is_empty());
+        match &self.array {
+            Array::Internal(internal) => {
+                internal.first_child.try_for_each_settled(f)?;
+                for branch in internal.elements.borrow().iter() {
+                    f(&branch.key, &branch.value)?;
+                    branch.child.try_for_each_settled(f)?;
+                }
+            }
+            Array::Leaf(leaf) => {
+                for (key, value) in leaf.elements.borrow().iter() {
+                    f(key, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of items in a flushed tree.
+    fn count_settled(&self) -> usize {
+        match &self.array {
+            Array::Internal(internal) => {
+                let elements = internal.elements.borrow();
+                internal.first_child.count_settled()
+                    + elements.len()
+                    + elements
+                        .iter()
+                        .map(|b| b.child.count_settled())
+                        .sum::<usize>()
+            }
+            Array::Leaf(leaf) => leaf.elements.borrow().len(),
+        }
+    }
 }
 
 enum Array<K, V> {
@@ -221,6 +569,26 @@ enum Array<K, V> {
 struct InternalArray<K, V> {
     first_child: Box<Node<K, V>>,
     elements: RefCell<Box<ArrayVec<Branch<K, V>, B>>>,
+    /// Whether lookups may have boxed a branch's key or value in this
+    /// subtree since it was last unboxed. Set on every node on the path
+    /// to such a branch.
+    boxed: Cell<bool>,
+}
+
+impl<K, V> InternalArray<K, V> {
+    fn new(first_child: Box<Node<K, V>>, elements: Box<ArrayVec<Branch<K, V>, B>>) -> Self {
+        InternalArray {
+            first_child,
+            elements: RefCell::new(elements),
+            boxed: Cell::new(false),
+        }
+    }
+
+    fn note_boxed(&self, visitor: &impl Visitor<K, V>) {
+        if visitor.boxed() {
+            self.boxed.set(true);
+        }
+    }
 }
 
 struct LeafArray<K, V> {
@@ -228,22 +596,18 @@ struct LeafArray<K, V> {
 }
 
 struct Branch<K, V> {
-    key: K,
-    stable_deref_key: Option<Box<K>>,
+    key: MaybeBox<K>,
     value: MaybeBox<V>,
     child: Box<Node<K, V>>,
 }
 
-impl<K: Clone, V> Branch<K, V> {
-    fn boxify_key(&mut self) -> *const K {
-        if let Some(k) = &self.stable_deref_key {
-            k.as_ref() as *const _
-        } else {
-            let k = Box::new(self.key.clone());
-            let result = k.as_ref() as *const _;
-            self.stable_deref_key = Some(k);
-            result
-        }
+impl<K, V> Branch<K, V> {
+    /// Puts a newer item with the same key in place of the separator, and
+    /// hands the old one to the visitor as superseded.
+    fn replace(&mut self, key: K, value: V, visitor: &mut impl Visitor<K, V>) {
+        let key = replace(&mut self.key, MaybeBox::Inline(key));
+        let value = replace(&mut self.value, MaybeBox::Inline(value));
+        visitor.superseded(key.into_inner(), value.into_inner());
     }
 }
 
@@ -252,7 +616,22 @@ enum MaybeBox<V> {
     Boxed(Box<V>),
 }
 
+// Moving a value between inline and boxed storage runs no user code, so
+// the closures passed to `replace_with_or_abort` below can't panic.
 impl<V> MaybeBox<V> {
+    fn unbox(&mut self) {
+        if let MaybeBox::Boxed(_) = self {
+            replace_with_or_abort(self, |this| MaybeBox::Inline(this.into_inner()));
+        }
+    }
+
+    fn into_inner(self) -> V {
+        match self {
+            MaybeBox::Inline(v) => v,
+            MaybeBox::Boxed(b) => *b,
+        }
+    }
+
     fn boxify(&mut self) -> *mut V {
         replace_with_or_abort(self, |this| match this {
             MaybeBox::Inline(v) => MaybeBox::Boxed(Box::new(v)),
@@ -286,256 +665,293 @@ impl<V> DerefMut for MaybeBox<V> {
 }
 
 impl<K: Ord, V> Node<K, V> {
-    fn insert(&mut self, key: K, value: V) {
-        let mut buffer = self.buffer.borrow_mut();
-        if self.buffer_is_sorted.get()
-            && let Some((front, _)) = buffer.front()
-        {
-            if front > &key {
-                buffer.push_front((key, value));
-                return;
-            } else {
-                let (back, _) = buffer.back().unwrap();
-                if back > &key {
-                    self.buffer_is_sorted.set(false);
-                }
-            }
+    /// Adds levels above the root until it has taken in all of `new_branches`.
+    fn grow(&mut self, mut new_branches: Vec<Branch<K, V>>, visitor: &mut impl Visitor<K, V>) {
+        while !new_branches.is_empty() {
+            let placeholder = Node {
+                buffer: Default::default(),
+                array: Array::Leaf(LeafArray {
+                    elements: Default::default(),
+                }),
+            };
+            let root = replace(self, placeholder);
+            // The old root's branches may be split off into the new one.
+            let boxed = matches!(&root.array, Array::Internal(internal) if internal.boxed.get());
+            let internal = InternalArray::new(Box::new(root), Default::default());
+            internal.boxed.set(boxed);
+            *self = Node {
+                buffer: Default::default(),
+                array: Array::Internal(internal),
+            };
+            let Array::Internal(internal) = &self.array else {
+                unreachable!()
+            };
+            new_branches = internal.insert_branches([(0, new_branches)], visitor);
         }
-        buffer.push_back((key, value));
+    }
+
+    fn insert(&self, key: K, value: V) {
+        self.buffer.borrow_mut().push(key, value);
     }
 
     fn append(&self, thief: SliceThief<(K, V)>, is_sorted: bool) {
-        let mut buffer = self.buffer.borrow_mut();
-        buffer.reserve(thief.len());
-        if is_sorted
-            && self.buffer_is_sorted.get()
-            && let Some((front, _)) = buffer.front()
-        {
-            let last = thief.peek_last();
-            if front > &last.0 {
-                for item in thief.rev() {
-                    buffer.push_front(item);
+        self.buffer.borrow_mut().append(thief, is_sorted);
+    }
+}
+
+thread_local! {
+    /// Plans kept between push-downs and merges, so that working one out
+    /// doesn't allocate.
+    static SEGMENTS: Cell<Vec<Segment>> = const { Cell::new(Vec::new()) };
+    static STEPS: Cell<Vec<Step>> = const { Cell::new(Vec::new()) };
+}
+
+/// A run of items in a buffer being pushed down.
+#[derive(Clone, Copy)]
+enum Segment {
+    /// Goes to the buffer of the child at index `child`.
+    Push {
+        child: usize,
+        len: usize,
+        placement: Placement,
+    },
+    /// Replaces the separator at index `separator`, one item after another.
+    Replace { separator: usize, len: usize },
+}
+
+impl<K: Ord, V> InternalArray<K, V> {
+    /// Works out where each of the sorted `items` goes when pushed down,
+    /// without moving anything, into `segments`.
+    fn plan_push_down(&self, items: &[(K, V)], segments: &mut Vec<Segment>) {
+        let elements = self.elements.borrow();
+        segments.clear();
+        let push = |segments: &mut Vec<Segment>, child: usize, slice: &[(K, V)]| {
+            if let (Some(first), Some(last)) = (slice.first(), slice.last()) {
+                let node = if child == 0 {
+                    &*self.first_child
+                } else {
+                    &*elements[child - 1].child
+                };
+                let placement = node.buffer.borrow().placement(&first.0, &last.0);
+                segments.push(Segment::Push {
+                    child,
+                    len: slice.len(),
+                    placement,
+                });
+            }
+        };
+
+        let (mut start, mut end, mut separator) = (0, 0, 0);
+        while end < items.len() && separator < elements.len() {
+            count!(comparisons);
+            match items[end].0.cmp(&elements[separator].key) {
+                Ordering::Less => end += 1,
+                Ordering::Equal => {
+                    push(segments, separator, &items[start..end]);
+                    match segments.last_mut() {
+                        Some(Segment::Replace { separator: s, len }) if *s == separator => {
+                            *len += 1
+                        }
+                        _ => segments.push(Segment::Replace { separator, len: 1 }),
+                    }
+                    end += 1;
+                    start = end;
                 }
-            } else {
-                let (back, _) = buffer.back().unwrap();
-                let first = thief.peek_first();
-                if back > &first.0 {
-                    self.buffer_is_sorted.set(false);
+                Ordering::Greater => {
+                    push(segments, separator, &items[start..end]);
+                    start = end;
+                    separator += 1;
                 }
-                buffer.extend(thief);
             }
-        } else {
-            self.buffer_is_sorted
-                .set(is_sorted && self.buffer_is_sorted.get());
-            buffer.extend(thief);
         }
+        push(segments, separator, &items[start..]);
     }
-}
 
-impl<K: Ord, V> InternalArray<K, V> {
-    fn push_down(&self, buffer: &mut Vec<(K, V)>) {
+    /// Moves the sorted `buffer` out as planned by
+    /// [`plan_push_down`](InternalArray::plan_push_down). Nothing is compared.
+    fn push_down(
+        &self,
+        buffer: &mut Vec<(K, V)>,
+        segments: &[Segment],
+        visitor: &mut impl Visitor<K, V>,
+    ) {
+        let mut pushed = 0;
         let mut elements = self.elements.borrow_mut();
-        let mut elements_iter = elements.iter_mut();
-
         let mut slicer = VecSlicer::new(buffer);
-
-        let mut push_to = &self.first_child;
-
-        if let Some(mut active_element) = elements_iter.next() {
-            loop {
-                let next_insert = slicer.current();
-                if next_insert.0 < active_element.key {
-                    slicer.advance(1);
-                    if slicer.remaining() == 0 {
-                        break;
-                    }
-                } else if next_insert.0 == active_element.key {
-                    let slice = slicer.slice();
-                    if slice.len() != 0 {
-                        push_to.append(slice, true);
-                    }
-                    let next_insert = slicer.take();
-                    active_element.key = next_insert.0;
-                    active_element.value = MaybeBox::Inline(next_insert.1);
-                    if slicer.remaining() == 0 {
-                        break;
-                    }
-                } else {
-                    let slice = slicer.slice();
-                    if slice.len() != 0 {
-                        push_to.append(slice, true);
-                    }
-                    push_to = &active_element.child;
-                    if let Some(ae) = elements_iter.next() {
-                        active_element = ae;
+        for &segment in segments {
+            match segment {
+                Segment::Push {
+                    child,
+                    len,
+                    placement,
+                } => {
+                    let node = if child == 0 {
+                        &*self.first_child
                     } else {
-                        break;
+                        &*elements[child - 1].child
+                    };
+                    slicer.advance(len);
+                    node.buffer
+                        .borrow_mut()
+                        .append_placed(slicer.slice(), placement);
+                    pushed += len;
+                }
+                Segment::Replace { separator, len } => {
+                    for _ in 0..len {
+                        let (key, value) = slicer.take();
+                        elements[separator].replace(key, value, visitor);
                     }
                 }
             }
         }
-
-        let last_slice = slicer.slice_to_end();
-        if last_slice.len() != 0 {
-            push_to.append(last_slice, true);
+        if pushed != 0 {
+            count!(items_pushed_down, pushed);
+            visitor.pushed_down(pushed);
         }
     }
 
-    fn process_branches(
+    /// Puts the branches split off children of this node right after those
+    /// children, splitting this node in turn if it overflows. Nothing is
+    /// compared, since the position of each child already orders them.
+    ///
+    /// Called on the way back up from the children, so it also notes
+    /// whether the visitor boxed anything below.
+    fn insert_branches(
         &self,
-        branches: impl ExactSizeIterator<Item = Branch<K, V>>,
+        splits: impl IntoIterator<Item = (usize, Vec<Branch<K, V>>)>,
+        visitor: &mut impl Visitor<K, V>,
     ) -> Vec<Branch<K, V>> {
-        process_buffer(
-            self.elements.borrow_mut(),
-            branches,
-            |branch| {
-                let child = Box::new(Node {
+        // Before splitting, so that the nodes split off inherit the flag.
+        self.note_boxed(visitor);
+        let mut splits = splits
+            .into_iter()
+            .filter(|(_, branches)| !branches.is_empty())
+            .peekable();
+        if splits.peek().is_none() {
+            return vec![];
+        }
+
+        let mut elements = self.elements.borrow_mut();
+        let mut old = take(&mut **elements).into_iter();
+        let mut merged = Vec::new();
+        let mut kept = 0;
+        for (child, branches) in splits {
+            merged.extend(old.by_ref().take(child - kept));
+            kept = child;
+            merged.extend(branches);
+        }
+        merged.extend(old);
+
+        let total_count = merged.len();
+        let new_branches = distribute(&mut elements, merged, total_count, |branch, elements| {
+            let internal = InternalArray::new(branch.child, elements);
+            internal.boxed.set(self.boxed.get());
+            Branch {
+                child: Box::new(Node {
                     buffer: Default::default(),
-                    buffer_is_sorted: Cell::new(true),
-                    array: Array::Internal(InternalArray {
-                        first_child: branch.child,
-                        elements: Default::default(),
-                    }),
-                });
-                let push_to = match &child.array {
-                    Array::Internal(ia) => {
-                        let mut elem_borrow = ia.elements.borrow_mut();
-                        &mut **elem_borrow as *mut _
-                    }
-                    _ => unreachable!(),
-                };
-                (Branch { child, ..branch }, push_to)
-            },
-            |b1, b2| b1.key.cmp(&b2.key),
-        )
+                    array: Array::Internal(internal),
+                }),
+                ..branch
+            }
+        });
+        drop(elements);
+        if !new_branches.is_empty() {
+            visitor.split(new_branches.len());
+        }
+        new_branches
     }
 }
 
 impl<K: Ord, V> LeafArray<K, V> {
-    fn process_buffer(&self, buffer: impl ExactSizeIterator<Item = (K, V)>) -> Vec<Branch<K, V>> {
-        process_buffer(
-            self.elements.borrow_mut(),
-            buffer,
-            |(key, value)| {
-                let child = Box::new(Node {
+    /// Merges the sorted `buffer` into this leaf as planned by
+    /// [`plan_merge`], splitting the leaf if it overflows.
+    fn process_buffer(
+        &self,
+        buffer: Vec<(K, V)>,
+        steps: &[Step],
+        visitor: &mut impl Visitor<K, V>,
+    ) -> Vec<Branch<K, V>> {
+        let mut elements = self.elements.borrow_mut();
+        let total_count = buffer.len() + elements.len();
+        if total_count <= B && buffer.len() <= 2 {
+            // A few items are cheaper to insert in place.
+            let mut buffer = buffer.into_iter();
+            let mut position = 0;
+            for step in steps {
+                match step {
+                    Step::Keep(n) => position += n,
+                    Step::Insert(n) => {
+                        for item in buffer.by_ref().take(*n) {
+                            elements.insert(position, item);
+                            position += 1;
+                        }
+                    }
+                    Step::Replace => {
+                        let (key, value) = replace(&mut elements[position], buffer.next().unwrap());
+                        visitor.superseded(key, value);
+                        position += 1;
+                    }
+                    Step::Skip => {
+                        let (key, value) = buffer.next().unwrap();
+                        visitor.superseded(key, value);
+                    }
+                }
+            }
+            return vec![];
+        }
+        let merged = apply_merge(take(&mut **elements), buffer, steps, |(key, value)| {
+            visitor.superseded(key, value)
+        });
+        let new_branches = distribute(
+            &mut elements,
+            merged,
+            total_count,
+            |(key, value), elements| Branch {
+                key: MaybeBox::Inline(key),
+                value: MaybeBox::Inline(value),
+                child: Box::new(Node {
                     buffer: Default::default(),
-                    buffer_is_sorted: Cell::new(true),
                     array: Array::Leaf(LeafArray {
-                        elements: Default::default(),
+                        elements: RefCell::new(elements),
                     }),
-                });
-                let push_to = match &child.array {
-                    Array::Leaf(la) => {
-                        let mut elem_borrow = la.elements.borrow_mut();
-                        &mut **elem_borrow as *mut _
-                    }
-                    _ => unreachable!(),
-                };
-                (
-                    Branch {
-                        key,
-                        stable_deref_key: None,
-                        value: MaybeBox::Inline(value),
-                        child,
-                    },
-                    push_to,
-                )
+                }),
             },
-            |(k1, _), (k2, _)| k1.cmp(k2),
-        )
+        );
+        drop(elements);
+        if !new_branches.is_empty() {
+            visitor.split(new_branches.len());
+        }
+        new_branches
     }
 }
 
-#[allow(clippy::type_complexity)]
-fn process_buffer<I, K, V>(
-    mut elements_ref: RefMut<Box<ArrayVec<I, B>>>,
-    buffer: impl ExactSizeIterator<Item = I>,
-    branch_builder: fn(I) -> (Branch<K, V>, *mut ArrayVec<I, B>),
-    item_comparator: fn(&I, &I) -> Ordering,
+/// Refills the empty `elements` with `items`, in order. Once more than `B`
+/// items are expected, every `B / 2 + 1`th item becomes the separator of a
+/// new branch, which `branch_builder` makes with the items that follow.
+fn distribute<I, K, V>(
+    elements: &mut ArrayVec<I, B>,
+    items: Vec<I>,
+    total_count: usize,
+    mut branch_builder: impl FnMut(I, Box<ArrayVec<I, B>>) -> Branch<K, V>,
 ) -> Vec<Branch<K, V>> {
-    let total_count = buffer.len() + elements_ref.len();
-
-    if total_count <= B && buffer.len() <= 2 {
-        for item in buffer {
-            match elements_ref.binary_search_by(|i| item_comparator(i, &item)) {
-                Ok(i) => elements_ref[i] = item,
-                Err(i) => elements_ref.insert(i, item),
+    debug_assert!(elements.is_empty());
+    let mut result = vec![];
+    let mut piece: Option<(I, Box<ArrayVec<I, B>>)> = None;
+    for (counter, item) in items.into_iter().enumerate() {
+        if (counter + 1) % (B / 2 + 1) == 0 && total_count - counter > B / 2 {
+            count!(splits);
+            if let Some((separator, items)) = piece.replace((item, Box::default())) {
+                result.push(branch_builder(separator, items));
             }
-        }
-        vec![]
-    } else {
-        let mut elements_vec = take(&mut *elements_ref);
-        let mut elements = elements_vec.drain(..);
-        let mut buffer = buffer.peekable();
-
-        let mut next_element = elements.next();
-        let mut next_insert = buffer.next();
-
-        let mut counter = 0;
-
-        let mut result = vec![];
-        let mut push_to = &mut **elements_ref as *mut ArrayVec<I, B>;
-        let mut apply = |item| {
-            if (counter + 1) % (B / 2 + 1) == 0 && total_count - counter > B / 2 {
-                let (new_branch, new_push_to) = branch_builder(item);
-                push_to = new_push_to;
-                result.push(new_branch);
-            } else {
-                unsafe { &mut *push_to }.push(item)
-            }
-
-            counter += 1;
-        };
-
-        // Consumes consecutive equal items from buffer, keeping only the last
-        #[inline]
-        fn take_last_duplicate<I>(
-            mut item: I,
-            buffer: &mut Peekable<impl Iterator<Item = I>>,
-            item_comparator: fn(&I, &I) -> Ordering,
-        ) -> I {
-            while let Some(peek) = buffer.peek()
-                && item_comparator(&item, peek).is_eq()
-            {
-                item = buffer.next().unwrap();
-            }
-            item
-        }
-
-        while let Some(ne) = &next_element
-            && let Some(ni) = &next_insert
-        {
-            match item_comparator(ne, ni) {
-                Ordering::Less => {
-                    apply(next_element.take().unwrap());
-                    next_element = elements.next();
-                }
-                Ordering::Greater => {
-                    apply(take_last_duplicate(
-                        next_insert.take().unwrap(),
-                        &mut buffer,
-                        item_comparator,
-                    ));
-                    next_insert = buffer.next();
-                }
-                Ordering::Equal => {
-                    next_element = elements.next();
-                }
+        } else {
+            match &mut piece {
+                Some((_, items)) => items.push(item),
+                None => elements.push(item),
             }
         }
-
-        while let Some(ne) = next_element {
-            apply(ne);
-            next_element = elements.next();
-        }
-
-        while let Some(ni) = next_insert {
-            apply(take_last_duplicate(ni, &mut buffer, item_comparator));
-            next_insert = buffer.next();
-        }
-
-        result
     }
+    result.extend(piece.map(|(separator, items)| branch_builder(separator, items)));
+    result
 }
 
 struct Flush;
@@ -559,7 +975,13 @@ impl<K, V> Visitor<K, V> for Flush {
 
 #[cfg(test)]
 mod tests {
-    use std::rc::Rc;
+    use std::{
+        cmp::Ordering,
+        collections::BTreeMap,
+        panic::{AssertUnwindSafe, catch_unwind},
+        rc::Rc,
+        sync::atomic::{AtomicUsize, Ordering::Relaxed},
+    };
 
     use rand::seq::SliceRandom;
 
@@ -584,6 +1006,7 @@ mod tests {
         assert_eq!(**map.get(&(B * B)).unwrap(), 1);
         assert_eq!(Rc::strong_count(&x), 2);
         assert_eq!(Rc::strong_count(&y), 2);
+        map.check_invariants().unwrap();
 
         // STEP 2:
         // Fill the map with a bunch of junk and call flush.
@@ -595,6 +1018,7 @@ mod tests {
             map.insert(i, Rc::new(i));
         }
         map.flush();
+        map.check_invariants().unwrap();
 
         // STEP 3:
         // Insert a bunch of duplicates into the structured map.
@@ -610,6 +1034,7 @@ mod tests {
         assert_eq!(**map.get(&(max - 1)).unwrap(), max - 1);
         assert_eq!(Rc::strong_count(&x), 1001);
         assert_eq!(Rc::strong_count(&y), 1001);
+        map.check_invariants().unwrap();
 
         // STEP 4:
         // Query B*B. This visits that leaf node and forces
@@ -617,6 +1042,7 @@ mod tests {
         assert_eq!(**map.get(&(B * B)).unwrap(), 1);
         assert_eq!(Rc::strong_count(&x), 1001);
         assert_eq!(Rc::strong_count(&y), 2);
+        map.check_invariants().unwrap();
 
         // STEP 5:
         // Flush again. Now the duplicates at index 0 should
@@ -626,6 +1052,36 @@ mod tests {
         assert_eq!(**map.get(&(B * B)).unwrap(), 1);
         assert_eq!(Rc::strong_count(&x), 2);
         assert_eq!(Rc::strong_count(&y), 2);
+        map.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn interleaved_sorted_batches() {
+        let mut map = Map::new();
+        for batch in 0..4 {
+            let mut vec: Vec<_> = (0..B * B).map(|i| (i * 2 + batch % 2, batch)).collect();
+            map.extend_from_sorted_vec(&mut vec);
+        }
+        for i in 0..B * B * 2 {
+            assert_eq!(map.get(&i), Some(&(2 + i % 2)));
+        }
+        map.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn insert_separator_key() {
+        let mut map = Map::new();
+        for i in 0..B * 3 {
+            map.insert(i, i);
+        }
+        map.flush();
+
+        // Each of these ends up as the last item of a buffer being pushed
+        // down, and some of them are equal to a separator key.
+        for i in 0..B * 3 {
+            map.insert(i, 0);
+            assert_eq!(map.get(&i), Some(&0));
+        }
     }
 
     #[test]
@@ -731,4 +1187,90 @@ mod tests {
         drop(map);
         assert_eq!(Rc::strong_count(&item), 1);
     }
+
+    #[test]
+    fn panicking_ord_leaves_a_valid_map() {
+        /// Comparisons left until one panics.
+        static FUSE: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+        #[derive(PartialEq, Eq, Debug)]
+        struct Key(usize);
+
+        impl Ord for Key {
+            fn cmp(&self, other: &Self) -> Ordering {
+                if FUSE.fetch_sub(1, Relaxed) == 1 {
+                    panic!("fuse blown");
+                }
+                self.0.cmp(&other.0)
+            }
+        }
+
+        impl PartialOrd for Key {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                Some(self.cmp(other))
+            }
+        }
+
+        let mut map = Map::new();
+        let mut expected = BTreeMap::new();
+        let mut blown = 0;
+        for (round, fuse) in [1, 7, 100, 1000, 5000, 20000, 80000]
+            .into_iter()
+            .enumerate()
+        {
+            let mut keys: Vec<_> = (0..B * 20).map(|i| (i * 7 + round) % (B * 30)).collect();
+            keys.shuffle(&mut rand::rng());
+            // An older value for one of the keys, which a sort cut short
+            // by the panic must not move past the newer one.
+            map.insert(Key(keys[B]), round + 100);
+            for key in keys {
+                map.insert(Key(key), round);
+                expected.insert(key, round);
+            }
+
+            FUSE.store(fuse, Relaxed);
+            let result = if round % 2 == 0 {
+                catch_unwind(AssertUnwindSafe(|| map.flush()))
+            } else {
+                catch_unwind(AssertUnwindSafe(|| map.flush_parallel()))
+            };
+            FUSE.store(usize::MAX, Relaxed);
+            blown += usize::from(result.is_err());
+            map.check_invariants().unwrap();
+        }
+        assert!(blown > 0);
+
+        map.flush();
+        map.check_invariants().unwrap();
+        for (&key, value) in &expected {
+            assert_eq!(map.get(&Key(key)), Some(value));
+        }
+        assert_eq!(map.stats().buffered, 0);
+    }
+
+    #[test]
+    fn panicking_drop_of_superseded_value() {
+        struct Bomb(bool);
+
+        impl Drop for Bomb {
+            fn drop(&mut self) {
+                if self.0 {
+                    panic!("bomb");
+                }
+            }
+        }
+
+        let mut map = Map::new();
+        for i in 0..B * 3 {
+            map.insert(i, Bomb(false));
+        }
+        map.insert(B, Bomb(true));
+        map.insert(B, Bomb(false));
+        assert!(catch_unwind(AssertUnwindSafe(|| map.flush())).is_err());
+
+        map.check_invariants().unwrap();
+        for i in 0..B * 3 {
+            assert!(!map.get(&i).unwrap().0);
+        }
+    }
 }
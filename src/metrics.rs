@@ -0,0 +1,153 @@
+use std::{cell::Cell, ops::AddAssign};
+
+use crate::Map;
+
+/// Counts of the work done by a [`Map`], from [`Map::metrics`].
+///
+/// Only available with the `metrics` feature. Without it, nothing is
+/// counted and maps don't carry any counters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Key comparisons made while searching, pushing down and merging into
+    /// nodes. Sorting buffers is counted by [`sorts`](Metrics::sorts) instead.
+    pub comparisons: u64,
+    /// The number of `get`-style queries.
+    pub lookups: u64,
+    /// The nodes searched by lookups.
+    pub nodes_visited: u64,
+    /// Items moved from a buffer into a child's buffer.
+    pub items_pushed_down: u64,
+    /// New nodes split off while merging a buffer into a full array.
+    pub splits: u64,
+    /// Buffers that needed merging or sorting before being processed.
+    pub sorts: u64,
+}
+
+impl AddAssign for Metrics {
+    fn add_assign(&mut self, other: Metrics) {
+        self.comparisons += other.comparisons;
+        self.lookups += other.lookups;
+        self.nodes_visited += other.nodes_visited;
+        self.items_pushed_down += other.items_pushed_down;
+        self.splits += other.splits;
+        self.sorts += other.sorts;
+    }
+}
+
+thread_local! {
+    /// Counts for the operation in progress on this thread. Nodes don't know
+    /// which map they belong to, so the map collects these when it's done.
+    static PENDING: Cell<Metrics> = const { Cell::new(Metrics {
+        comparisons: 0,
+        lookups: 0,
+        nodes_visited: 0,
+        items_pushed_down: 0,
+        splits: 0,
+        sorts: 0,
+    }) };
+}
+
+/// Updates the counts for the operation in progress on this thread.
+pub(crate) fn add(f: impl FnOnce(&mut Metrics)) {
+    PENDING.with(|pending| {
+        let mut metrics = pending.get();
+        f(&mut metrics);
+        pending.set(metrics);
+    });
+}
+
+/// Takes the counts for the operation in progress on this thread.
+pub(crate) fn take() -> Metrics {
+    PENDING.with(Cell::take)
+}
+
+impl<K, V> Map<K, V> {
+    /// The counts collected since the map was created or last reset.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.get()
+    }
+
+    pub fn reset_metrics(&self) {
+        self.metrics.take();
+    }
+
+    /// Adds the counts of the operation that just finished on this thread.
+    /// Only lookups count the nodes they visit.
+    pub(crate) fn collect_metrics(&self, lookup: bool) {
+        let mut pending = take();
+        if lookup {
+            pending.lookups += 1;
+        } else {
+            pending.nodes_visited = 0;
+        }
+        let mut metrics = self.metrics.get();
+        metrics += pending;
+        self.metrics.set(metrics);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::seq::SliceRandom;
+
+    use crate::{B, Map, Metrics};
+
+    #[test]
+    fn lookups_visit_one_node_per_level() {
+        let mut map = Map::new();
+        map.extend_sorted_iter((0..B * B).map(|i| (i * 2, i)));
+        map.flush();
+        let height = map.stats().height as u64;
+        map.reset_metrics();
+
+        for i in 0..100 {
+            map.get(&(i * 2 + 1));
+        }
+        let metrics = map.metrics();
+        assert_eq!(metrics.lookups, 100);
+        assert_eq!(metrics.nodes_visited, height * 100);
+        assert!(metrics.comparisons >= metrics.nodes_visited);
+        assert_eq!(metrics.items_pushed_down, 0);
+
+        map.reset_metrics();
+        assert_eq!(map.metrics(), Metrics::default());
+    }
+
+    #[test]
+    fn push_downs_splits_and_sorts() {
+        let mut map = Map::new();
+        for i in 0..B * B {
+            map.insert(i, i);
+        }
+        map.flush();
+        let metrics = map.metrics();
+        assert!(metrics.splits > B as u64);
+        assert_eq!(metrics.sorts, 0);
+        assert_eq!(metrics.nodes_visited, 0);
+
+        map.reset_metrics();
+        let mut keys: Vec<_> = (0..B).map(|i| i * B + 1).collect();
+        keys.shuffle(&mut rand::rng());
+        for key in keys {
+            map.insert(key, key);
+        }
+        map.get(&1);
+        let metrics = map.metrics();
+        assert_eq!(metrics.sorts, 1);
+        assert!(metrics.items_pushed_down > 0);
+
+        map.reset_metrics();
+        let mut parallel = Map::new();
+        for i in (0..B * B).rev() {
+            parallel.insert(i, i);
+        }
+        parallel.flush();
+        parallel.reset_metrics();
+        for i in (0..B * B).rev() {
+            parallel.insert(i, i);
+        }
+        parallel.flush_parallel();
+        assert!(parallel.metrics().items_pushed_down > 0);
+        assert_eq!(map.metrics(), Metrics::default());
+    }
+}
@@ -0,0 +1,188 @@
+use crate::{Branch, Map, Motion, Visitor};
+
+/// Callbacks for the work a [`Map`] does while processing its buffers,
+/// installed with [`Map::set_observer`].
+///
+/// Every method does nothing by default.
+pub trait Observer<K, V> {
+    /// Called with an item that was dropped in favor of a newer item with
+    /// an equal key, in place of dropping it.
+    fn value_superseded(&mut self, _key: K, _value: V) {}
+
+    /// Called when a node splits off `new_nodes` siblings.
+    fn node_split(&mut self, _new_nodes: usize) {}
+
+    /// Called when `len` items of an internal node's buffer are pushed down
+    /// into its children's buffers.
+    fn buffer_pushed_down(&mut self, _len: usize) {}
+}
+
+impl<K, V> Map<K, V> {
+    /// Installs an observer, replacing the previous one.
+    ///
+    /// Without an observer, the map skips the callbacks entirely.
+    pub fn set_observer(&mut self, observer: impl Observer<K, V> + Send + 'static) {
+        *self.observer.get_mut() = Some(Box::new(observer));
+    }
+
+    /// Removes the observer, and returns it.
+    pub fn take_observer(&mut self) -> Option<Box<dyn Observer<K, V> + Send>> {
+        self.observer.get_mut().take()
+    }
+}
+
+/// Runs a visitor, and reports its traversal's events to an observer.
+pub(crate) struct Observed<'a, T, K, V> {
+    pub(crate) visitor: &'a mut T,
+    pub(crate) observer: &'a mut (dyn Observer<K, V> + Send),
+}
+
+impl<T: Visitor<K, V>, K, V> Visitor<K, V> for Observed<'_, T, K, V> {
+    #[inline]
+    fn visit_internal(&mut self, array: &mut [Branch<K, V>], temporary: bool) -> Motion {
+        self.visitor.visit_internal(array, temporary)
+    }
+
+    #[inline]
+    fn visit_leaf(&mut self, array: &mut [(K, V)]) {
+        self.visitor.visit_leaf(array);
+    }
+
+    #[inline]
+    fn visit_buffer(&mut self, len: usize) {
+        self.visitor.visit_buffer(len);
+    }
+
+    #[inline]
+    fn exhausted(&self) -> bool {
+        self.visitor.exhausted()
+    }
+
+    #[inline]
+    fn boxed(&self) -> bool {
+        self.visitor.boxed()
+    }
+
+    #[inline]
+    fn deliver(&mut self) -> Option<(K, V)> {
+        self.visitor.deliver()
+    }
+
+    fn superseded(&mut self, key: K, value: V) {
+        self.observer.value_superseded(key, value);
+    }
+
+    fn split(&mut self, new_nodes: usize) {
+        self.observer.node_split(new_nodes);
+    }
+
+    fn pushed_down(&mut self, len: usize) {
+        self.observer.buffer_pushed_down(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::Observer;
+    use crate::{B, InsertStrategy, Map};
+
+    #[derive(Default)]
+    struct Events {
+        superseded: Vec<(usize, usize)>,
+        new_nodes: usize,
+        pushed_down: usize,
+    }
+
+    struct Recorder(Arc<Mutex<Events>>);
+
+    impl Observer<usize, usize> for Recorder {
+        fn value_superseded(&mut self, key: usize, value: usize) {
+            self.0.lock().unwrap().superseded.push((key, value));
+        }
+
+        fn node_split(&mut self, new_nodes: usize) {
+            self.0.lock().unwrap().new_nodes += new_nodes;
+        }
+
+        fn buffer_pushed_down(&mut self, len: usize) {
+            self.0.lock().unwrap().pushed_down += len;
+        }
+    }
+
+    fn observed_map() -> (Map<usize, usize>, Arc<Mutex<Events>>) {
+        let events = Arc::new(Mutex::new(Events::default()));
+        let mut map = Map::new();
+        map.set_observer(Recorder(events.clone()));
+        (map, events)
+    }
+
+    #[test]
+    fn superseded_in_leaf() {
+        let (mut map, events) = observed_map();
+        map.insert(1, 10);
+        map.insert(1, 11);
+        map.insert(2, 20);
+        map.flush();
+        map.insert(1, 12);
+        assert_eq!(map.get(&1), Some(&12));
+        assert_eq!(events.lock().unwrap().superseded, [(1, 10), (1, 11)]);
+    }
+
+    #[test]
+    fn superseded_separator_and_splits() {
+        let (mut map, events) = observed_map();
+        for i in 0..B * B {
+            map.insert(i, i);
+        }
+        map.flush();
+        let stats = map.stats();
+        let nodes = stats.internal_nodes + stats.leaf_nodes;
+        {
+            let events = events.lock().unwrap();
+            assert!(events.superseded.is_empty());
+            // Every node but the first leaf was split off, or is a root.
+            assert_eq!(events.new_nodes + stats.height - 1, nodes - 1);
+        }
+
+        for i in 0..B * B {
+            map.insert(i, i + 1);
+        }
+        map.flush();
+        {
+            let events = events.lock().unwrap();
+            assert_eq!(events.superseded.len(), B * B);
+            assert!(events.superseded.iter().all(|&(key, value)| key == value));
+            assert!(events.pushed_down > 0);
+        }
+
+        // Eager inserts replace separators on their way down. Twice as
+        // many queries as inserts make the adaptive strategy eager.
+        map.set_insert_strategy(InsertStrategy::Adaptive);
+        for i in 0..B * B {
+            assert_eq!(map.get(&i), Some(&(i + 1)));
+            assert!(map.contains_key(&i));
+            map.insert(i, i + 2);
+        }
+        map.flush();
+        let events = events.lock().unwrap();
+        assert_eq!(events.superseded.len(), B * B * 2);
+        assert!(
+            events.superseded[B * B..]
+                .iter()
+                .all(|&(key, value)| value == key + 1)
+        );
+    }
+
+    #[test]
+    fn take_observer() {
+        let (mut map, events) = observed_map();
+        assert!(map.take_observer().is_some());
+        map.insert(1, 1);
+        map.insert(1, 2);
+        map.flush();
+        assert!(events.lock().unwrap().superseded.is_empty());
+        assert!(map.take_observer().is_none());
+    }
+}
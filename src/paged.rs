@@ -0,0 +1,876 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    mem::take,
+    path::Path,
+};
+
+use crate::{
+    B,
+    buffer::Buffer,
+    codec::{Codec, Decoder, Encoder, ReadError},
+    flat::{self, FlatNode, children_toward, push_down, search, settle_children, settle_leaf},
+    unwind,
+    vec_slicer::VecSlicer,
+};
+
+const MAGIC: [u8; 4] = *b"LZPG";
+const VERSION: u16 = 1;
+
+const PAGE_SIZE: usize = 4096;
+/// Each page starts with the ID of the next page in its chain,
+/// and the number of bytes of the chain that it holds.
+const PAGE_HEADER: usize = 12;
+
+/// Inserts push a buffer down as soon as it holds more than this many
+/// items, which bounds the memory taken by each cached node.
+const BUFFER_LIMIT: usize = B * 8;
+
+/// Page 0 holds the file header, so it never identifies a node.
+type PageId = u64;
+const NO_PAGE: PageId = 0;
+
+/// A map stored in a file, with only a bounded number of nodes in memory.
+///
+/// Each node, including its buffer, is serialized into a chain of
+/// fixed-size pages through the [`Codec`]s of its keys and values, and is
+/// identified by the first page of its chain. Nodes are loaded into a page
+/// cache on demand, and written back when they are evicted from the cache
+/// or when the map is [flushed](PagedMap::flush). Dropping the map flushes
+/// it, ignoring errors.
+///
+/// The file header, which locates the root, is only written by `flush`.
+/// Until then, nodes are written copy-on-write: a node that was part of
+/// the file at the last flush is written to new pages rather than over its
+/// old ones, and those old pages are only reused after the next flush. If
+/// the process dies in between, opening the file gives the map as it was
+/// at the last flush.
+///
+/// Like [`Map`](crate::Map), inserts go to the root's buffer and queries
+/// push buffers down along their path. On top of that, a buffer that grows
+/// past a fixed bound is pushed down by the insert that filled it.
+///
+/// After an I/O error, the contents of the map are unspecified.
+pub struct PagedMap<K: Ord + Codec, V: Codec> {
+    pager: RefCell<Pager<K, V>>,
+}
+
+type PagedNode<K, V> = FlatNode<K, V, PageId>;
+
+struct Cached<K, V> {
+    node: PagedNode<K, V>,
+    /// The pages the node was last written to, or will be written to.
+    pages: Vec<PageId>,
+    dirty: bool,
+    last_used: u64,
+}
+
+struct Header {
+    root: PageId,
+    length: u64,
+    /// The chain of pages that lists the pages free for reuse.
+    free_list: PageId,
+    page_count: u64,
+}
+
+/// Moves nodes between the file and the page cache.
+///
+/// Nodes are identified by the page their parent refers to them by, which
+/// is where the file held them at the last flush, or where they were first
+/// written if they are newer than that. Nodes that have moved since are
+/// found through `moved` until their parent is written.
+///
+/// A node is only ever changed on a path down from the root, so whenever a
+/// node is dirty, so is its parent. Writing a node writes its dirty children
+/// first, which keeps it that way, and means that the parent of a node in
+/// `moved` is always in memory.
+struct Pager<K, V> {
+    file: File,
+    header: Header,
+    capacity: usize,
+    cache: HashMap<PageId, Cached<K, V>>,
+    /// The cached nodes by the time they were last used.
+    lru: BTreeMap<u64, PageId>,
+    clock: u64,
+    /// Where the nodes that were written to new pages since the last flush
+    /// start now, by the page their parent refers to.
+    moved: HashMap<PageId, PageId>,
+    /// The pages that are free for reuse.
+    free: Vec<PageId>,
+    /// The pages that the file still uses as of the last flush, but that
+    /// are free for reuse after the next.
+    released: Vec<PageId>,
+    /// The pages allocated since the last flush, which the file doesn't
+    /// use yet, so they can be overwritten.
+    fresh: HashSet<PageId>,
+    /// The pages that hold the free list as of the last flush.
+    free_list: Vec<PageId>,
+}
+
+enum Settle<'a, K> {
+    /// Empty the buffers on the path to a key.
+    Path(&'a K),
+    /// Empty every buffer.
+    All,
+    /// Push down the buffers holding more than [`BUFFER_LIMIT`] items.
+    Overflow,
+}
+
+// Derived impls would require `K: Copy`.
+impl<K> Clone for Settle<'_, K> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K> Copy for Settle<'_, K> {}
+
+type Split<K, V> = ((K, V), PageId);
+
+impl<K: Ord + Codec, V: Codec> PagedMap<K, V> {
+    /// Creates an empty map at `path`, replacing any file already there.
+    /// At most `cache_capacity` nodes are kept in memory at a time, apart
+    /// from those on the path being worked on.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cache_capacity` is 0.
+    pub fn create(path: impl AsRef<Path>, cache_capacity: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mut pager = Pager::new(
+            file,
+            Header {
+                root: NO_PAGE,
+                length: 0,
+                free_list: NO_PAGE,
+                page_count: 1,
+            },
+            cache_capacity,
+        );
+        pager.header.root = pager.new_node(PagedNode::new(Vec::new(), Vec::new()))?;
+        pager.flush()?;
+        Ok(PagedMap {
+            pager: RefCell::new(pager),
+        })
+    }
+
+    /// Opens a map created by [`create`](PagedMap::create).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cache_capacity` is 0.
+    pub fn open(path: impl AsRef<Path>, cache_capacity: usize) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut page = vec![0; PAGE_SIZE];
+        file.read_exact(&mut page)?;
+        let header = Header::decode(&page).map_err(invalid_data)?;
+        let mut pager = Pager::new(file, header, cache_capacity);
+        pager.read_free_list()?;
+        Ok(PagedMap {
+            pager: RefCell::new(pager),
+        })
+    }
+
+    /// The number of items ever inserted, counted like
+    /// [`Map::len`](crate::Map::len).
+    pub fn len(&self) -> usize {
+        self.pager.borrow().header.length as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> io::Result<()> {
+        let pager = self.pager.get_mut();
+        pager.header.length += 1;
+        let root = pager.header.root;
+        let mut cached = pager.take(root)?;
+        // The root has to go back into the cache even if `Ord` panics.
+        unwind::catch(|| cached.node.buffer.push(key, value));
+        cached.dirty = true;
+        let overflowing = cached.node.buffer.len() > BUFFER_LIMIT;
+        pager.put(root, cached)?;
+        unwind::resume();
+        if overflowing {
+            pager.settle_root(Settle::Overflow)?;
+        }
+        Ok(())
+    }
+
+    /// Looks up `key`, and calls `f` on its value.
+    pub fn get_with<R>(&self, key: &K, f: impl FnOnce(&V) -> R) -> io::Result<Option<R>> {
+        let mut pager = self.pager.borrow_mut();
+        pager.settle_root(Settle::Path(key))?;
+
+        // The nodes on the path stay out of the cache until the end, so
+        // that evictions can't move the child that comes next.
+        let mut path = Vec::new();
+        let mut f = Some(f);
+        let mut id = pager.header.root;
+        let result = loop {
+            let cached = match pager.take(id) {
+                Ok(cached) => cached,
+                Err(error) => break Err(error),
+            };
+            let node = &cached.node;
+            let next = match unwind::catch(|| search(&node.elements, key)) {
+                Some(Ok(i)) => Err(f
+                    .take()
+                    .and_then(|f| unwind::catch(|| f(&node.elements[i].1)))),
+                Some(Err(_)) if node.children.is_empty() => Err(None),
+                Some(Err(i)) => Ok(node.children[i]),
+                None => Err(None),
+            };
+            path.push((id, cached));
+            match next {
+                Ok(child) => id = child,
+                Err(result) => break Ok(result),
+            }
+        };
+        let restored = path
+            .into_iter()
+            .rev()
+            .try_for_each(|(id, cached)| pager.put(id, cached));
+        unwind::resume();
+        restored?;
+        result
+    }
+
+    pub fn get(&self, key: &K) -> io::Result<Option<V>>
+    where
+        V: Clone,
+    {
+        self.get_with(key, V::clone)
+    }
+
+    /// Pushes every buffered item down to its leaf.
+    pub fn push_down_all(&mut self) -> io::Result<()> {
+        self.pager.get_mut().settle_root(Settle::All)
+    }
+
+    /// Writes every changed node and then the file header back to the
+    /// file, and waits for the data to reach the disk. Once this returns,
+    /// the file holds the map as it is now.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.pager.get_mut().flush()
+    }
+}
+
+impl<K: Ord + Codec, V: Codec> Drop for PagedMap<K, V> {
+    fn drop(&mut self) {
+        let _ = self.pager.get_mut().flush();
+    }
+}
+
+impl<K: Ord + Codec, V: Codec> Pager<K, V> {
+    fn new(file: File, header: Header, capacity: usize) -> Self {
+        assert!(
+            capacity > 0,
+            "the page cache needs room for at least one node"
+        );
+        Pager {
+            file,
+            header,
+            capacity,
+            cache: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            moved: HashMap::new(),
+            free: Vec::new(),
+            released: Vec::new(),
+            fresh: HashSet::new(),
+            free_list: Vec::new(),
+        }
+    }
+
+    /// Takes a node out of the cache, loading it if it isn't there. The node
+    /// can't be evicted until it is [put](Pager::put) back.
+    fn take(&mut self, id: PageId) -> io::Result<Cached<K, V>> {
+        match self.uncache(id) {
+            Some(cached) => Ok(cached),
+            None => self.read_node(self.moved.get(&id).copied().unwrap_or(id)),
+        }
+    }
+
+    /// Returns a node to the cache, evicting the least recently used
+    /// nodes if the cache is full.
+    fn put(&mut self, id: PageId, mut cached: Cached<K, V>) -> io::Result<()> {
+        self.clock += 1;
+        cached.last_used = self.clock;
+        self.cache(id, cached);
+
+        while self.cache.len() > self.capacity {
+            let (_, id) = self.lru.pop_first().unwrap();
+            let mut cached = self.cache.remove(&id).unwrap();
+            if cached.dirty {
+                let new_id = self.write_back(&mut cached)?;
+                if new_id != id {
+                    self.moved.insert(id, new_id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn cache(&mut self, id: PageId, cached: Cached<K, V>) {
+        self.lru.insert(cached.last_used, id);
+        self.cache.insert(id, cached);
+    }
+
+    fn uncache(&mut self, id: PageId) -> Option<Cached<K, V>> {
+        let cached = self.cache.remove(&id)?;
+        self.lru.remove(&cached.last_used);
+        Some(cached)
+    }
+
+    fn new_node(&mut self, node: PagedNode<K, V>) -> io::Result<PageId> {
+        let id = self.allocate();
+        let cached = Cached {
+            node,
+            pages: vec![id],
+            dirty: true,
+            last_used: 0,
+        };
+        self.put(id, cached)?;
+        Ok(id)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Every dirty node has a dirty parent, so writing back the root
+        // writes all of them.
+        let root = self.header.root;
+        let mut new_root = self.moved.remove(&root).unwrap_or(root);
+        if let Some(mut cached) = self.uncache(root) {
+            let result = match cached.dirty {
+                true => self.write_back(&mut cached),
+                false => Ok(new_root),
+            };
+            new_root = *result.as_ref().unwrap_or(&new_root);
+            self.cache(new_root, cached);
+            result?;
+        }
+        self.header.root = new_root;
+        debug_assert!(self.moved.is_empty());
+
+        // The pages given up since the last flush are still in use until
+        // the header is written, so they can't hold the new free list.
+        let mut released = take(&mut self.released);
+        released.append(&mut self.free_list);
+        let mut free_list = Vec::new();
+        while free_list.len() * (PAGE_SIZE - PAGE_HEADER) < (self.free.len() + released.len()) * 8 {
+            free_list.push(self.allocate());
+        }
+        let bytes: Vec<u8> = self
+            .free
+            .iter()
+            .chain(&released)
+            .flat_map(|id| id.to_le_bytes())
+            .collect();
+        self.write_chain(&free_list, &bytes)?;
+        self.header.free_list = free_list.first().copied().unwrap_or(NO_PAGE);
+        self.free_list = free_list;
+        self.file.sync_data()?;
+
+        let mut page = vec![0; PAGE_SIZE];
+        self.header.encode(&mut page);
+        self.write_page(0, &page)?;
+        self.file.sync_data()?;
+        self.free.append(&mut released);
+        self.fresh.clear();
+        Ok(())
+    }
+
+    fn read_free_list(&mut self) -> io::Result<()> {
+        if self.header.free_list == NO_PAGE {
+            return Ok(());
+        }
+        let (pages, bytes) = self.read_chain(self.header.free_list)?;
+        if bytes.len() % 8 != 0 {
+            return Err(invalid_data(ReadError::Corrupt("bad free list")));
+        }
+        self.free = bytes
+            .chunks_exact(8)
+            .map(|id| u64::from_le_bytes(id.try_into().unwrap()))
+            .collect();
+        self.free_list = pages;
+        Ok(())
+    }
+
+    /// Settles the root as described by `mode`, growing the tree if it splits.
+    ///
+    /// A panic from `Ord` is rethrown once every node is back in the cache.
+    fn settle_root(&mut self, mode: Settle<K>) -> io::Result<()> {
+        let result = self.settle_and_grow(mode);
+        unwind::resume();
+        result
+    }
+
+    fn settle_and_grow(&mut self, mode: Settle<K>) -> io::Result<()> {
+        let (mut splits, _) = self.settle(self.header.root, mode)?;
+        while !splits.is_empty() {
+            let (elements, mut children): (Vec<_>, Vec<_>) = splits.into_iter().unzip();
+            children.insert(0, self.header.root);
+            let mut node = PagedNode::new(elements, children);
+            splits = self.split(&mut node)?;
+            self.header.root = self.new_node(node)?;
+        }
+        Ok(())
+    }
+
+    /// Settles a node as described by `mode`, and returns the splits that
+    /// its parent has to take in, and whether the node is dirty.
+    fn settle(&mut self, id: PageId, mode: Settle<K>) -> io::Result<(Vec<Split<K, V>>, bool)> {
+        let mut cached = self.take(id)?;
+        let result = self.settle_node(&mut cached, mode);
+        let dirty = cached.dirty;
+        self.put(id, cached)?;
+        Ok((result?, dirty))
+    }
+
+    fn settle_node(
+        &mut self,
+        cached: &mut Cached<K, V>,
+        mode: Settle<K>,
+    ) -> io::Result<Vec<Split<K, V>>> {
+        let node = &mut cached.node;
+        if node.is_leaf() {
+            let settle = match mode {
+                Settle::Overflow => node.buffer.len() > BUFFER_LIMIT,
+                Settle::Path(_) | Settle::All => !node.buffer.is_empty(),
+            };
+            if !settle {
+                return Ok(Vec::new());
+            }
+            cached.dirty = true;
+            settle_leaf(&mut node.buffer, &mut node.elements);
+            return self.split(node);
+        }
+
+        let mut overflowing = Vec::new();
+        if !node.buffer.is_empty() {
+            cached.dirty = true;
+            let mut batches = Vec::new();
+            push_down(&mut node.buffer, &mut node.elements, |i, slice| {
+                batches.push((i, slice.collect::<Vec<_>>()));
+            });
+            for (i, mut batch) in batches {
+                let child_id = node.children[i];
+                let mut child = self.take(child_id)?;
+                flat::append(
+                    &mut child.node.buffer,
+                    VecSlicer::new(&mut batch).slice_to_end(),
+                );
+                child.dirty = true;
+                if child.node.buffer.len() > BUFFER_LIMIT {
+                    overflowing.push(i);
+                }
+                self.put(child_id, child)?;
+            }
+        }
+
+        let indices = match mode {
+            Settle::Path(key) => children_toward(&node.elements, Some(key)).collect(),
+            Settle::All => children_toward(&node.elements, None).collect(),
+            Settle::Overflow => overflowing,
+        };
+        let mut dirty = false;
+        settle_children(
+            &mut node.elements,
+            &mut node.children,
+            indices.into_iter(),
+            |&mut child| {
+                let (splits, child_dirty) = self.settle(child, mode)?;
+                dirty |= child_dirty || !splits.is_empty();
+                Ok::<_, io::Error>(splits)
+            },
+        )?;
+        cached.dirty |= dirty;
+        self.split(node)
+    }
+
+    fn split(&mut self, node: &mut PagedNode<K, V>) -> io::Result<Vec<Split<K, V>>> {
+        flat::split(&mut node.elements, &mut node.children)
+            .into_iter()
+            .map(|(separator, elements, children)| {
+                let id = self.new_node(PagedNode::new(elements, children))?;
+                Ok((separator, id))
+            })
+            .collect()
+    }
+
+    fn read_node(&mut self, id: PageId) -> io::Result<Cached<K, V>> {
+        let (pages, bytes) = self.read_chain(id)?;
+        let node = PagedNode::decode(&bytes).map_err(invalid_data)?;
+        Ok(Cached {
+            node,
+            pages,
+            dirty: false,
+            last_used: 0,
+        })
+    }
+
+    /// Writes a dirty node back, after its dirty children that are in the
+    /// cache, and returns the page it starts at now.
+    fn write_back(&mut self, cached: &mut Cached<K, V>) -> io::Result<PageId> {
+        for i in 0..cached.node.children.len() {
+            let child = cached.node.children[i];
+            let mut new_id = self.moved.remove(&child).unwrap_or(child);
+            if let Some(mut child_cached) = self.uncache(child) {
+                let result = match child_cached.dirty {
+                    true => self.write_back(&mut child_cached),
+                    false => Ok(new_id),
+                };
+                new_id = *result.as_ref().unwrap_or(&new_id);
+                self.cache(new_id, child_cached);
+                result?;
+            }
+            cached.node.children[i] = new_id;
+        }
+
+        let mut bytes = Vec::new();
+        cached.node.encode(&mut bytes)?;
+        let len = bytes.len().div_ceil(PAGE_SIZE - PAGE_HEADER);
+
+        // The file still uses the pages that the node had at the last
+        // flush, so the node moves to new ones.
+        if !self.fresh.contains(&cached.pages[0]) {
+            for id in take(&mut cached.pages) {
+                self.release(id);
+            }
+        }
+        while cached.pages.len() < len {
+            let id = self.allocate();
+            cached.pages.push(id);
+        }
+        for id in cached.pages.split_off(len) {
+            self.release(id);
+        }
+        self.write_chain(&cached.pages, &bytes)?;
+        cached.dirty = false;
+        Ok(cached.pages[0])
+    }
+
+    fn read_chain(&mut self, first: PageId) -> io::Result<(Vec<PageId>, Vec<u8>)> {
+        let mut pages = Vec::new();
+        let mut bytes = Vec::new();
+        let mut page = vec![0; PAGE_SIZE];
+        let mut next = first;
+        while next != NO_PAGE {
+            if next >= self.header.page_count || pages.len() as u64 >= self.header.page_count {
+                return Err(invalid_data(ReadError::Corrupt("bad page chain")));
+            }
+            self.read_page(next, &mut page)?;
+            pages.push(next);
+            let used = u32::from_le_bytes(page[8..12].try_into().unwrap()) as usize;
+            if used > PAGE_SIZE - PAGE_HEADER {
+                return Err(invalid_data(ReadError::Corrupt("bad page")));
+            }
+            bytes.extend_from_slice(&page[PAGE_HEADER..PAGE_HEADER + used]);
+            next = u64::from_le_bytes(page[..8].try_into().unwrap());
+        }
+        Ok((pages, bytes))
+    }
+
+    /// Writes `bytes` as a chain of `pages`, which must be enough to hold
+    /// them. Pages past the end of `bytes` are left empty.
+    fn write_chain(&mut self, pages: &[PageId], bytes: &[u8]) -> io::Result<()> {
+        let mut page = vec![0; PAGE_SIZE];
+        let mut chunks = bytes.chunks(PAGE_SIZE - PAGE_HEADER);
+        debug_assert!(chunks.len() <= pages.len());
+        for i in 0..pages.len() {
+            let chunk = chunks.next().unwrap_or_default();
+            let next = pages.get(i + 1).copied().unwrap_or(NO_PAGE);
+            page[..8].copy_from_slice(&next.to_le_bytes());
+            page[8..12].copy_from_slice(&(chunk.len() as u32).to_le_bytes());
+            page[PAGE_HEADER..PAGE_HEADER + chunk.len()].copy_from_slice(chunk);
+            page[PAGE_HEADER + chunk.len()..].fill(0);
+            self.write_page(pages[i], &page)?;
+        }
+        Ok(())
+    }
+
+    fn allocate(&mut self) -> PageId {
+        let id = self.free.pop().unwrap_or_else(|| {
+            self.header.page_count += 1;
+            self.header.page_count - 1
+        });
+        self.fresh.insert(id);
+        id
+    }
+
+    /// Gives up a page, which can be reused right away unless the file
+    /// still uses it.
+    fn release(&mut self, id: PageId) {
+        if self.fresh.contains(&id) {
+            self.free.push(id);
+        } else {
+            self.released.push(id);
+        }
+    }
+
+    fn read_page(&mut self, id: PageId, page: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+        self.file.read_exact(page)
+    }
+
+    fn write_page(&mut self, id: PageId, page: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(id * PAGE_SIZE as u64))?;
+        self.file.write_all(page)
+    }
+}
+
+impl Header {
+    fn encode(&self, page: &mut [u8]) {
+        page[..4].copy_from_slice(&MAGIC);
+        page[4..6].copy_from_slice(&VERSION.to_le_bytes());
+        page[6..10].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+        for (i, field) in [self.root, self.length, self.free_list, self.page_count]
+            .into_iter()
+            .enumerate()
+        {
+            page[10 + i * 8..18 + i * 8].copy_from_slice(&field.to_le_bytes());
+        }
+    }
+
+    fn decode(page: &[u8]) -> Result<Self, ReadError> {
+        if page[..4] != MAGIC {
+            return Err(ReadError::BadMagic);
+        }
+        let version = u16::from_le_bytes(page[4..6].try_into().unwrap());
+        if version != VERSION {
+            return Err(ReadError::UnsupportedVersion(version));
+        }
+        if u32::from_le_bytes(page[6..10].try_into().unwrap()) != PAGE_SIZE as u32 {
+            return Err(ReadError::Corrupt("unsupported page size"));
+        }
+        let field = |i: usize| u64::from_le_bytes(page[10 + i * 8..18 + i * 8].try_into().unwrap());
+        Ok(Header {
+            root: field(0),
+            length: field(1),
+            free_list: field(2),
+            page_count: field(3),
+        })
+    }
+}
+
+impl<K: Ord + Codec, V: Codec> FlatNode<K, V, PageId> {
+    fn encode(&self, out: &mut Vec<u8>) -> io::Result<()> {
+        let mut encoder = Encoder::new(out);
+        encoder.u64(self.buffer.len() as u64)?;
+        for (key, value) in self.buffer.iter() {
+            encoder.encode(key)?;
+            encoder.encode(value)?;
+        }
+        encoder.u64(self.elements.len() as u64)?;
+        for (key, value) in &self.elements {
+            encoder.encode(key)?;
+            encoder.encode(value)?;
+        }
+        encoder.u64(self.children.len() as u64)?;
+        for child in &self.children {
+            encoder.u64(*child)?;
+        }
+        encoder.finish()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, ReadError> {
+        let mut decoder = Decoder::new(bytes);
+        // Pushing the items would compare them, and nodes are read in the
+        // middle of settling, where a panicking `Ord` can't be allowed.
+        let mut items = Vec::new();
+        for _ in 0..decoder.u64()? {
+            items.push((decoder.decode()?, decoder.decode()?));
+        }
+        let buffer = Buffer::from_unsorted(items);
+        let len = decoder.u64()?;
+        if len > B as u64 {
+            return Err(ReadError::Corrupt("node too large"));
+        }
+        let mut elements = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            elements.push((decoder.decode()?, decoder.decode()?));
+        }
+        let children_len = decoder.u64()?;
+        if children_len != 0 && children_len != len + 1 {
+            return Err(ReadError::Corrupt("wrong number of children"));
+        }
+        let mut children = Vec::with_capacity(children_len as usize);
+        for _ in 0..children_len {
+            children.push(decoder.u64()?);
+        }
+        decoder.finish()?;
+        Ok(FlatNode {
+            buffer,
+            elements,
+            children,
+        })
+    }
+}
+
+fn invalid_data(error: ReadError) -> io::Error {
+    match error {
+        ReadError::Io(error) => error,
+        error => io::Error::new(io::ErrorKind::InvalidData, error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BUFFER_LIMIT, PagedMap};
+    use crate::{
+        B,
+        codec::Codec,
+        flat::tests::{Fused, check_panicking_ord},
+    };
+
+    #[test]
+    fn insert_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut map = PagedMap::create(dir.path().join("map"), 8).unwrap();
+        let max = (B * B * 2) as u64;
+        for i in 0..max {
+            map.insert(i * 2, i.to_string()).unwrap();
+        }
+        // Far more nodes than fit in the cache have been written back.
+        assert!(map.pager.borrow().header.page_count > 100);
+        for i in (0..max).step_by(7) {
+            assert_eq!(map.get(&(i * 2)).unwrap(), Some(i.to_string()));
+            assert_eq!(map.get(&(i * 2 + 1)).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        let max = (B * B) as u32;
+        {
+            let mut map = PagedMap::create(&path, 4).unwrap();
+            for i in 0..max {
+                map.insert(i, i).unwrap();
+            }
+            for i in 0..100 {
+                map.insert(i, 0).unwrap();
+            }
+            assert_eq!(map.get(&(max - 1)).unwrap(), Some(max - 1));
+        }
+
+        let mut map = PagedMap::<u32, u32>::open(&path, 4).unwrap();
+        assert_eq!(map.len(), max as usize + 100);
+        map.push_down_all().unwrap();
+        for i in 0..max {
+            let expected = if i < 100 { 0 } else { i };
+            assert_eq!(map.get(&i).unwrap(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn reopen_without_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        let max = (B * B) as u32;
+        let mut map = PagedMap::create(&path, 4).unwrap();
+        for i in 0..max {
+            map.insert(i, i).unwrap();
+        }
+        map.flush().unwrap();
+        let page_count = map.pager.borrow().header.page_count;
+
+        // Rewrites every node, evicting most of them, and the root splits.
+        for i in 0..max * 2 {
+            map.insert(i, i + 1).unwrap();
+        }
+        map.push_down_all().unwrap();
+        assert!(map.pager.borrow().header.page_count > page_count);
+        // Like a crash, nothing is flushed.
+        std::mem::forget(map);
+
+        let mut map = PagedMap::<u32, u32>::open(&path, 4).unwrap();
+        assert_eq!(map.len(), max as usize);
+        map.push_down_all().unwrap();
+        for i in 0..max {
+            assert_eq!(map.get(&i).unwrap(), Some(i));
+        }
+        assert_eq!(map.get(&max).unwrap(), None);
+    }
+
+    #[test]
+    fn pages_are_reused_after_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut map = PagedMap::create(dir.path().join("map"), 4).unwrap();
+        let max = (B * B) as u32;
+        let mut page_counts = Vec::new();
+        for round in 0..4 {
+            for i in 0..max {
+                map.insert(i, round).unwrap();
+            }
+            map.push_down_all().unwrap();
+            map.flush().unwrap();
+            page_counts.push(map.pager.borrow().header.page_count);
+        }
+        // Each round moves every node to new pages, and can reuse the
+        // pages that the round before gave up.
+        assert!(page_counts[3] <= page_counts[1] + 2, "{page_counts:?}");
+        for i in (0..max).step_by(7) {
+            assert_eq!(map.get(&i).unwrap(), Some(3));
+        }
+    }
+
+    #[test]
+    fn large_buffers_span_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        let mut map = PagedMap::create(&path, 1).unwrap();
+        let value = "x".repeat(100);
+        for i in 0..BUFFER_LIMIT as u32 {
+            map.insert(i, value.clone()).unwrap();
+        }
+        map.flush().unwrap();
+        drop(map);
+
+        let map = PagedMap::<u32, String>::open(&path, 1).unwrap();
+        assert_eq!(map.get(&5).unwrap().as_ref(), Some(&value));
+    }
+
+    #[test]
+    fn open_bad_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map");
+        std::fs::write(&path, vec![1; 4096]).unwrap();
+        let err = PagedMap::<u32, u32>::open(&path, 1).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    impl Codec for Fused {
+        fn encode(&self, out: &mut Vec<u8>) {
+            self.0.encode(out);
+        }
+
+        fn decode(bytes: &[u8]) -> Option<Self> {
+            usize::decode(bytes).map(Fused)
+        }
+    }
+
+    #[test]
+    fn panicking_ord() {
+        let dir = tempfile::tempdir().unwrap();
+        check_panicking_ord(
+            &mut PagedMap::create(dir.path().join("map"), 8).unwrap(),
+            |map, key, value| map.insert(key, value).unwrap(),
+            |map, key| match key {
+                Some(key) => drop(map.get(key).unwrap()),
+                None => map.push_down_all().unwrap(),
+            },
+            |map, key| map.get(key).unwrap(),
+            PagedMap::len,
+        );
+    }
+}
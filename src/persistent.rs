@@ -0,0 +1,611 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    convert::Infallible,
+    mem::take,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use crate::{
+    B,
+    flat::{
+        self, FlatNode, children_toward, extend_buffer, push_down, search, settle_children,
+        settle_leaf,
+    },
+    unwind,
+};
+
+/// A map whose nodes are shared through `Arc`s, so that taking a
+/// [`snapshot`](PersistentMap::snapshot) of it takes constant time.
+///
+/// Changes copy the nodes they touch while a snapshot still shares them,
+/// and change the rest in place. That includes the buffers pushed down by
+/// queries, so queries on the map and on its snapshots never see each
+/// other's work. Inserts don't touch any nodes: they are kept in chunks
+/// that snapshots share, until the next query moves them into the root's
+/// buffer.
+///
+/// The read API is that of [`Snapshot`], which the map dereferences to.
+///
+/// This is a separate tree rather than [`Map`](crate::Map) with its nodes
+/// behind `Arc`s. `Map` changes its nodes in place through `&self`, in
+/// every visitor: buffers, item arrays and boxed values all sit in
+/// `RefCell`s. Sharing those nodes would mean every one of these writes
+/// first checking whether the node is shared and copying it. This map
+/// builds on the plain nodes that [`SyncMap`](crate::SyncMap) and
+/// [`PagedMap`](crate::PagedMap) use instead, which it can only change
+/// through [`Arc::make_mut`].
+/// Copying a shared node copies its items, hence `K: Clone` and
+/// `V: Clone`.
+pub struct PersistentMap<K, V> {
+    current: Snapshot<K, V>,
+}
+
+/// An immutable view of a [`PersistentMap`] at the time the snapshot was
+/// taken.
+///
+/// Snapshots are cheap to clone, and can be sent to other threads.
+/// Queries still push buffers down, into nodes private to the snapshot.
+///
+/// Lookups return references that live as long as the snapshot is
+/// borrowed. The snapshot holds on to each node that a lookup returned a
+/// reference into, so the node is shared and later queries copy it rather
+/// than change it. Once it holds on to more nodes than a tree of its items
+/// packed full would have, the snapshot empties all of its buffers. Its
+/// nodes don't change after that, so each one is held on to at most once
+/// more. The nodes are released when the snapshot is dropped or, for the
+/// map's own snapshot, at the next insert.
+pub struct Snapshot<K, V> {
+    root: RefCell<Arc<PNode<K, V>>>,
+    /// The items inserted since the last query, which the next query moves
+    /// into the root's buffer.
+    pending: RefCell<Option<Arc<Chunk<K, V>>>>,
+    length: usize,
+    /// The nodes that lookups returned references into, by address.
+    ///
+    /// A lookup takes `&self` but pushes buffers down, and nothing else
+    /// stops a later lookup from changing a node that an earlier one
+    /// returned a reference into. Holding an extra handle makes the node
+    /// shared, so it is copied rather than changed. Keying by address
+    /// holds each node once, however many references point into it.
+    pinned: RefCell<HashMap<usize, Arc<PNode<K, V>>>>,
+    /// Set when `pinned` grew large enough that all buffers were emptied,
+    /// and cleared along with `pinned`.
+    settled: Cell<bool>,
+}
+
+/// A piece of the items inserted into a [`PersistentMap`] that haven't
+/// reached the root's buffer yet, linked to the older pieces. Snapshots
+/// share the chunks, so inserting after a snapshot starts a new chunk
+/// rather than copying anything.
+struct Chunk<K, V> {
+    items: Vec<(K, V)>,
+    older: Option<Arc<Chunk<K, V>>>,
+}
+
+#[derive(Clone)]
+struct PNode<K, V>(FlatNode<K, V, Arc<PNode<K, V>>>);
+
+type Split<K, V> = ((K, V), Arc<PNode<K, V>>);
+
+impl<K, V> PersistentMap<K, V> {
+    pub fn new() -> Self {
+        PersistentMap {
+            current: Snapshot {
+                root: RefCell::new(Arc::new(PNode::new(Vec::new(), Vec::new()))),
+                pending: RefCell::new(None),
+                length: 0,
+                pinned: RefCell::default(),
+                settled: Cell::new(false),
+            },
+        }
+    }
+
+    /// Takes a snapshot of the map, sharing all of its nodes.
+    pub fn snapshot(&self) -> Snapshot<K, V> {
+        self.current.clone()
+    }
+}
+
+impl<K, V> Default for PersistentMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> Deref for PersistentMap<K, V> {
+    type Target = Snapshot<K, V>;
+
+    fn deref(&self) -> &Snapshot<K, V> {
+        &self.current
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> PersistentMap<K, V> {
+    /// Adds an item to the pending chunks, which the next query moves into
+    /// the root's buffer. This copies nothing, even after a snapshot.
+    pub fn insert(&mut self, key: K, value: V) {
+        let current = &mut self.current;
+        // No references from earlier lookups can still be alive.
+        current.pinned.get_mut().clear();
+        *current.settled.get_mut() = false;
+        current.length += 1;
+
+        let pending = current.pending.get_mut();
+        if let Some(chunk) = pending.as_mut().and_then(Arc::get_mut)
+            && chunk.items.len() < B
+        {
+            chunk.items.push((key, value));
+            return;
+        }
+        let mut items = Vec::with_capacity(B);
+        items.push((key, value));
+        *pending = Some(Arc::new(Chunk {
+            items,
+            older: pending.take(),
+        }));
+    }
+
+    /// Recursively processes all buffers in the map.
+    pub fn flush(&self) {
+        self.current.settle(None);
+    }
+}
+
+impl<K, V> Clone for Snapshot<K, V> {
+    fn clone(&self) -> Self {
+        Snapshot {
+            root: RefCell::new(self.root.borrow().clone()),
+            pending: RefCell::new(self.pending.borrow().clone()),
+            length: self.length,
+            pinned: RefCell::default(),
+            settled: Cell::new(false),
+        }
+    }
+}
+
+impl<K, V> Snapshot<K, V> {
+    /// The number of items inserted before the snapshot was taken, counted
+    /// like [`Map::len`](crate::Map::len).
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    /// Keeps `node` from changing for as long as the snapshot is borrowed,
+    /// and returns its `i`th element.
+    fn pin(&self, node: &Arc<PNode<K, V>>, i: usize) -> (&K, &V) {
+        let (key, value) = &node.elements[i];
+        self.pinned
+            .borrow_mut()
+            .entry(Arc::as_ptr(node) as usize)
+            .or_insert_with(|| node.clone());
+        // SAFETY: The node is kept alive by `pinned`, which is only cleared
+        // through `&mut self`. Since `pinned` shares the node, queries copy
+        // it instead of changing it in place.
+        unsafe { (&*(key as *const K), &*(value as *const V)) }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Snapshot<K, V> {
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.get_key_value(key).map(|(_, value)| value)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get_key_value(key).is_some()
+    }
+
+    pub fn get_key_value(&self, key: &K) -> Option<(&K, &V)> {
+        self.settle(Some(key));
+        let root = self.root.borrow();
+        let mut node = &*root;
+        loop {
+            match search(&node.elements, key) {
+                Ok(i) => return Some(self.pin(node, i)),
+                Err(_) if node.is_leaf() => return None,
+                Err(i) => node = &node.children[i],
+            }
+        }
+    }
+
+    pub fn get_before(&self, key: &K) -> Option<&V> {
+        self.before(key, false).map(|(_, value)| value)
+    }
+
+    pub fn get_before_inc(&self, key: &K) -> Option<&V> {
+        self.before(key, true).map(|(_, value)| value)
+    }
+
+    pub fn get_key_value_before(&self, key: &K) -> Option<(&K, &V)> {
+        self.before(key, false)
+    }
+
+    pub fn get_key_value_before_inc(&self, key: &K) -> Option<(&K, &V)> {
+        self.before(key, true)
+    }
+
+    fn before(&self, key: &K, inclusive: bool) -> Option<(&K, &V)> {
+        self.settle(Some(key));
+        let root = self.root.borrow();
+        let mut node = &*root;
+        let mut previous = None;
+        loop {
+            let i = match search(&node.elements, key) {
+                Ok(i) if inclusive => return Some(self.pin(node, i)),
+                Ok(i) | Err(i) => i,
+            };
+            if i != 0 {
+                previous = Some((node, i - 1));
+            }
+            if node.is_leaf() {
+                return previous.map(|(node, i)| self.pin(node, i));
+            }
+            node = &node.children[i];
+        }
+    }
+
+    /// Empties the buffers on the path to `key`, or all of them if `key` is
+    /// `None` or lookups have pinned too many nodes.
+    ///
+    /// A panic from `Ord` is rethrown once the tree is consistent again.
+    fn settle(&self, mut key: Option<&K>) {
+        let mut root = self.root.borrow_mut();
+        self.take_pending(&mut root);
+        if !self.settled.get() && self.pinned.borrow().len() > self.length / B + B {
+            self.settled.set(true);
+            key = None;
+        }
+        if let Some(splits) = root.settle(key) {
+            grow(&mut root, splits);
+        }
+        drop(root);
+        unwind::resume();
+    }
+
+    /// Moves the pending items into the root's buffer, oldest first.
+    fn take_pending(&self, root: &mut Arc<PNode<K, V>>) {
+        let mut chunks = Vec::new();
+        let mut next = self.pending.borrow_mut().take();
+        while let Some(chunk) = next {
+            match Arc::try_unwrap(chunk) {
+                Ok(mut chunk) => {
+                    next = chunk.older.take();
+                    chunks.push(take(&mut chunk.items));
+                }
+                Err(chunk) => {
+                    next = chunk.older.clone();
+                    chunks.push(chunk.items.clone());
+                }
+            }
+        }
+        if chunks.is_empty() {
+            return;
+        }
+        let buffer = &mut Arc::make_mut(root).buffer;
+        for items in chunks.into_iter().rev() {
+            extend_buffer(buffer, items);
+        }
+    }
+}
+
+impl<K, V> Drop for Chunk<K, V> {
+    fn drop(&mut self) {
+        // One chunk at a time, so that a long list can't overflow the stack.
+        let mut older = self.older.take();
+        while let Some(chunk) = older {
+            older = Arc::into_inner(chunk).and_then(|mut chunk| chunk.older.take());
+        }
+    }
+}
+
+/// Adds levels above the root until it has taken in all of `splits`.
+fn grow<K: Ord + Clone, V: Clone>(root: &mut Arc<PNode<K, V>>, mut splits: Vec<Split<K, V>>) {
+    while !splits.is_empty() {
+        let (elements, mut children): (Vec<_>, Vec<_>) = splits.into_iter().unzip();
+        children.insert(0, root.clone());
+        let mut new_root = PNode::new(elements, children);
+        splits = new_root.split();
+        *root = Arc::new(new_root);
+    }
+}
+
+impl<K, V> PNode<K, V> {
+    fn new(elements: Vec<(K, V)>, children: Vec<Arc<PNode<K, V>>>) -> Self {
+        PNode(FlatNode::new(elements, children))
+    }
+}
+
+impl<K, V> Deref for PNode<K, V> {
+    type Target = FlatNode<K, V, Arc<PNode<K, V>>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<K, V> DerefMut for PNode<K, V> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> PNode<K, V> {
+    /// Empties the buffers on the path to `key`, or in the whole subtree if
+    /// `key` is `None`, copying the nodes that are shared.
+    ///
+    /// Returns `None` if there was nothing to empty, leaving the node as it
+    /// was. Otherwise returns the splits that the parent has to take in.
+    fn settle(self: &mut Arc<Self>, key: Option<&K>) -> Option<Vec<Split<K, V>>> {
+        if self.buffer.is_empty() {
+            if self.is_leaf() {
+                return None;
+            }
+            if Arc::get_mut(self).is_none() {
+                return self.settle_below_shared(key);
+            }
+        }
+
+        let FlatNode {
+            buffer,
+            elements,
+            children,
+        } = &mut Arc::make_mut(self).0;
+        if children.is_empty() {
+            settle_leaf(buffer, elements);
+            return Some(Arc::make_mut(self).split());
+        }
+
+        push_down(buffer, elements, |i, slice| {
+            flat::append(&mut Arc::make_mut(&mut children[i]).buffer, slice);
+        });
+        let indices = children_toward(elements, key);
+        let Ok(()) = settle_children(elements, children, indices, |child| {
+            Ok::<_, Infallible>(child.settle(key).unwrap_or_default())
+        });
+        Some(Arc::make_mut(self).split())
+    }
+
+    /// Settles the children of a shared node with an empty buffer, and only
+    /// copies the node if one of them changed.
+    fn settle_below_shared(self: &mut Arc<Self>, key: Option<&K>) -> Option<Vec<Split<K, V>>> {
+        let mut settled: Vec<_> = children_toward(&self.elements, key)
+            .filter_map(|i| {
+                // The child is shared through this node, so a copy of the
+                // handle makes it count as such.
+                let mut child = self.children[i].clone();
+                child.settle(key).map(|splits| (i, child, splits))
+            })
+            .collect();
+        if settled.is_empty() {
+            return None;
+        }
+
+        let indices: Vec<_> = settled.iter().map(|(i, _, _)| *i).collect();
+        let FlatNode {
+            elements, children, ..
+        } = &mut Arc::make_mut(self).0;
+        let Ok(()) = settle_children(elements, children, indices.into_iter(), |child| {
+            let (_, settled_child, splits) = settled.pop().unwrap();
+            *child = settled_child;
+            Ok::<_, Infallible>(splits)
+        });
+        Some(Arc::make_mut(self).split())
+    }
+
+    fn split(&mut self) -> Vec<Split<K, V>> {
+        flat::split(&mut self.0.elements, &mut self.0.children)
+            .into_iter()
+            .map(|(separator, elements, children)| {
+                (separator, Arc::new(PNode::new(elements, children)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use rand::seq::SliceRandom;
+
+    use super::{PNode, PersistentMap};
+    use crate::{B, flat::tests::check_panicking_ord};
+
+    fn nodes<K, V>(node: &PNode<K, V>) -> usize {
+        1 + node
+            .children
+            .iter()
+            .map(|child| nodes(child))
+            .sum::<usize>()
+    }
+
+    #[test]
+    fn snapshot_isolation() {
+        let mut map = PersistentMap::new();
+        let max = B * B * 3;
+        for i in 0..max {
+            map.insert(i * 2, i);
+        }
+        let before = map.snapshot();
+        for i in 0..max {
+            map.insert(i * 2, i + 1);
+            map.insert(i * 2 + 1, i);
+        }
+        let after = map.snapshot();
+        map.insert(0, 0);
+
+        for i in 0..max {
+            assert_eq!(before.get(&(i * 2)), Some(&i));
+            assert_eq!(before.get(&(i * 2 + 1)), None);
+            assert_eq!(after.get(&(i * 2 + 1)), Some(&i));
+            assert_eq!(map.get(&(i * 2 + 1)), Some(&i));
+        }
+        assert_eq!(map.get(&0), Some(&0));
+        assert_eq!(after.get(&0), Some(&1));
+        assert_eq!(before.len(), max);
+        assert_eq!(after.len(), max * 3);
+    }
+
+    #[test]
+    fn settled_nodes_stay_shared() {
+        let mut map = PersistentMap::new();
+        for i in 0..B * B {
+            map.insert(i, i);
+        }
+        map.flush();
+
+        let snapshot = map.snapshot();
+        assert_eq!(snapshot.get(&5), Some(&5));
+        assert_eq!(map.get(&5), Some(&5));
+        assert!(Arc::ptr_eq(&map.root.borrow(), &snapshot.root.borrow()));
+
+        // Only the path to the changed key is copied.
+        map.insert(5, 0);
+        assert_eq!(map.get(&5), Some(&0));
+        let root = map.root.borrow();
+        let snapshot_root = snapshot.root.borrow();
+        assert!(!Arc::ptr_eq(&root, &snapshot_root));
+        let shared = root
+            .children
+            .iter()
+            .zip(&snapshot_root.children)
+            .filter(|(a, b)| Arc::ptr_eq(a, b))
+            .count();
+        assert_eq!(shared, root.children.len() - 1);
+    }
+
+    #[test]
+    fn inserts_after_snapshots_copy_nothing() {
+        let mut map = PersistentMap::new();
+        for i in 0..B * B {
+            map.insert(i, i);
+        }
+        map.flush();
+        for i in 0..B * B {
+            map.insert(i, i + 1);
+        }
+
+        let mut snapshots = vec![];
+        for i in 0..B * 3 {
+            snapshots.push(map.snapshot());
+            map.insert(i, 0);
+            assert!(Arc::ptr_eq(&map.root.borrow(), &snapshots[0].root.borrow()));
+        }
+        // Each snapshot has the inserts made before it, and none after.
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            for j in 0..B * 3 {
+                let expected = if j < i { 0 } else { j + 1 };
+                assert_eq!(snapshot.get(&j), Some(&expected));
+            }
+        }
+        assert_eq!(map.get(&0), Some(&0));
+        assert_eq!(map.get(&(B * 3)), Some(&(B * 3 + 1)));
+    }
+
+    #[test]
+    fn pinned_nodes_are_bounded() {
+        let mut map = PersistentMap::new();
+        let max = B * B * 3;
+        for i in 0..max {
+            map.insert(i, i);
+        }
+        map.flush();
+        for i in (0..max).step_by(2) {
+            map.insert(i, i + 1);
+        }
+
+        let snapshot = map.snapshot();
+        let mut keys: Vec<_> = (0..max).collect();
+        keys.shuffle(&mut rand::rng());
+        for &i in &keys {
+            assert_eq!(snapshot.get(&i), Some(&(i + 1 - i % 2)));
+        }
+        // Each node can be pinned once after all buffers were emptied.
+        assert!(snapshot.settled.get());
+        let limit = max / B + B + 1 + nodes(&snapshot.root.borrow());
+        assert!(snapshot.pinned.borrow().len() <= limit);
+    }
+
+    #[test]
+    fn references_outlive_later_queries() {
+        let mut map = PersistentMap::new();
+        let max = B * B * 3;
+        for i in 0..max {
+            map.insert(i, i.to_string());
+        }
+        let snapshot = map.snapshot();
+        let first = snapshot.get(&0).unwrap();
+        let (key, last) = snapshot.get_key_value_before(&max).unwrap();
+        // These push buffers down through the nodes the references point into.
+        for i in 0..max {
+            map.insert(i, String::new());
+            assert_eq!(snapshot.get(&i), Some(&i.to_string()));
+        }
+        assert_eq!(first, "0");
+        assert_eq!((*key, last), (max - 1, &(max - 1).to_string()));
+    }
+
+    #[test]
+    fn get_before() {
+        let mut map = PersistentMap::new();
+        for i in 0..B * B {
+            map.insert(i * 2, i);
+        }
+        let snapshot = map.snapshot();
+        for i in 0..B * B {
+            assert_eq!(snapshot.get_before(&(i * 2 + 1)), Some(&i));
+            assert_eq!(snapshot.get_before_inc(&(i * 2)), Some(&i));
+            assert_eq!(
+                snapshot
+                    .get_key_value_before(&(i * 2))
+                    .map(|(k, v)| (*k, *v)),
+                i.checked_sub(1).map(|j| (j * 2, j))
+            );
+            assert_eq!(
+                snapshot.get_key_value_before_inc(&(i * 2)),
+                Some((&(i * 2), &i))
+            );
+        }
+        assert_eq!(map.get_before(&0), None);
+    }
+
+    #[test]
+    fn snapshot_on_other_thread() {
+        let mut map = PersistentMap::new();
+        let max = B * B;
+        for i in 0..max {
+            map.insert(i, i);
+        }
+        let snapshot = map.snapshot();
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..max {
+                    assert_eq!(snapshot.get(&i), Some(&i));
+                }
+            });
+            for i in 0..max {
+                map.insert(i, 0);
+            }
+        });
+        assert_eq!(map.get(&(max - 1)), Some(&0));
+    }
+
+    #[test]
+    fn panicking_ord() {
+        check_panicking_ord(
+            &mut PersistentMap::new(),
+            PersistentMap::insert,
+            |map, key| match key {
+                Some(key) => drop(map.get(key)),
+                None => map.flush(),
+            },
+            |map, key| map.get(key).copied(),
+            |map| map.len(),
+        );
+    }
+}
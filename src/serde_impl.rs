@@ -0,0 +1,88 @@
+use std::{fmt, marker::PhantomData};
+
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{MapAccess, Visitor},
+    ser::SerializeMap,
+};
+
+use crate::Map;
+
+/// Serializes the map as an ordered map, flushing it first.
+impl<K: Ord + Serialize, V: Serialize> Serialize for Map<K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.flush();
+        let root = self.root.borrow();
+        let mut map = serializer.serialize_map(Some(root.count_settled()))?;
+        root.try_for_each_settled(&mut |key, value| map.serialize_entry(key, value))?;
+        map.end()
+    }
+}
+
+/// Deserializes a map. Input that is already ordered by key is loaded
+/// bottom-up, and anything else is buffered at the root. Later entries
+/// win over earlier ones with the same key.
+impl<'de, K: Ord + Deserialize<'de>, V: Deserialize<'de>> Deserialize<'de> for Map<K, V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(MapVisitor(PhantomData))
+    }
+}
+
+struct MapVisitor<K, V>(PhantomData<fn() -> (K, V)>);
+
+impl<'de, K: Ord + Deserialize<'de>, V: Deserialize<'de>> Visitor<'de> for MapVisitor<K, V> {
+    type Value = Map<K, V>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let mut items = Vec::with_capacity(access.size_hint().unwrap_or(0).min(4096));
+        let mut is_sorted = true;
+        while let Some(item) = access.next_entry::<K, V>()? {
+            if let Some((last, _)) = items.last() {
+                is_sorted &= *last <= item.0;
+            }
+            items.push(item);
+        }
+
+        let mut map = Map::new();
+        if is_sorted {
+            map.extend_sorted_iter(items);
+        } else {
+            map.extend_from_vec(&mut items);
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{B, Map};
+
+    #[test]
+    fn round_trip() {
+        let mut map = Map::new();
+        for i in (0..B * 3).rev() {
+            map.insert(i, i.to_string());
+        }
+        map.insert(0, String::from("zero"));
+
+        let json = serde_json::to_string(&map).unwrap();
+        assert!(json.starts_with(r#"{"0":"zero","1":"1","2":"2","#));
+
+        let map: Map<usize, String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(map.len(), B * 3);
+        assert_eq!(map.get(&0).unwrap(), "zero");
+        assert_eq!(map.get(&(B * 3 - 1)).unwrap(), &(B * 3 - 1).to_string());
+    }
+
+    #[test]
+    fn deserialize_unsorted() {
+        let map: Map<u32, u32> = serde_json::from_str(r#"{"3":0,"1":1,"3":3,"2":2}"#).unwrap();
+        assert_eq!(map.get(&1), Some(&1));
+        assert_eq!(map.get(&3), Some(&3));
+        assert_eq!(map.get(&4), None);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{Array, B, Map, MaybeBox, Node};
+
+/// A summary of the shape of a [`Map`]'s tree, from [`Map::stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of levels, counting the root and the leaves.
+    pub height: usize,
+    pub internal_nodes: usize,
+    pub leaf_nodes: usize,
+    /// `fill[n]` is the number of nodes whose array holds `n` elements.
+    pub fill: Vec<usize>,
+    /// Per-level details, from the root down to the leaves.
+    pub levels: Vec<LevelStats>,
+    /// The number of items waiting in buffers, across all levels.
+    pub buffered: usize,
+    /// The number of non-empty buffers whose items aren't in key order.
+    pub unsorted_buffers: usize,
+    /// The number of branch values moved to the heap to be handed out by
+    /// reference.
+    pub boxed_values: usize,
+    /// The number of branch keys moved to the heap to be handed out by
+    /// reference.
+    pub boxed_keys: usize,
+}
+
+/// The part of [`Stats`] about one level of the tree.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LevelStats {
+    pub nodes: usize,
+    /// The number of elements in the arrays of the level's nodes.
+    pub elements: usize,
+    /// The number of items waiting in the level's buffers.
+    pub buffered: usize,
+}
+
+impl<K, V> Map<K, V> {
+    /// Walks the whole tree and reports on its shape.
+    ///
+    /// Unlike queries, this doesn't push down any buffers.
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            fill: vec![0; B + 1],
+            ..Default::default()
+        };
+        self.root.borrow().add_stats(&mut stats, 0);
+        stats.height = stats.levels.len();
+        stats
+    }
+}
+
+impl<K, V> Node<K, V> {
+    fn add_stats(&self, stats: &mut Stats, depth: usize) {
+        if stats.levels.len() == depth {
+            stats.levels.push(LevelStats::default());
+        }
+        let buffer = self.buffer.borrow();
+        stats.buffered += buffer.len();
+        stats.unsorted_buffers += usize::from(!buffer.is_sorted());
+
+        let elements = match &self.array {
+            Array::Internal(internal) => {
+                stats.internal_nodes += 1;
+                let elements = internal.elements.borrow();
+                internal.first_child.add_stats(stats, depth + 1);
+                for branch in elements.iter() {
+                    stats.boxed_values += usize::from(matches!(branch.value, MaybeBox::Boxed(_)));
+                    stats.boxed_keys += usize::from(matches!(branch.key, MaybeBox::Boxed(_)));
+                    branch.child.add_stats(stats, depth + 1);
+                }
+                elements.len()
+            }
+            Array::Leaf(leaf) => {
+                stats.leaf_nodes += 1;
+                leaf.elements.borrow().len()
+            }
+        };
+        stats.fill[elements] += 1;
+
+        let level = &mut stats.levels[depth];
+        level.nodes += 1;
+        level.elements += elements;
+        level.buffered += buffer.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{B, Map};
+
+    #[test]
+    fn empty() {
+        let stats = Map::<u32, u32>::new().stats();
+        assert_eq!(stats.height, 1);
+        assert_eq!((stats.internal_nodes, stats.leaf_nodes), (0, 1));
+        assert_eq!(stats.fill[0], 1);
+        assert_eq!(stats.buffered, 0);
+        assert_eq!(stats.unsorted_buffers, 0);
+    }
+
+    #[test]
+    fn buffers_and_levels() {
+        let mut map = Map::new();
+        let max = (B * B) as u32;
+        for i in (0..max).rev() {
+            map.insert(i * 2, i);
+        }
+        map.insert(1, 1);
+        map.insert(3, 3);
+        let stats = map.stats();
+        assert_eq!(stats.buffered, max as usize + 2);
+        assert_eq!(stats.levels[0].buffered, max as usize + 2);
+        assert_eq!(stats.unsorted_buffers, 1);
+
+        map.flush();
+        let stats = map.stats();
+        assert_eq!(stats.buffered, 0);
+        assert_eq!(stats.unsorted_buffers, 0);
+        assert!(stats.height >= 3);
+        assert_eq!(stats.height, stats.levels.len());
+        assert_eq!(stats.levels[0].nodes, 1);
+        let nodes: usize = stats.levels.iter().map(|l| l.nodes).sum();
+        assert_eq!(nodes, stats.internal_nodes + stats.leaf_nodes);
+        assert_eq!(stats.fill.iter().sum::<usize>(), nodes);
+        let elements: usize = stats.levels.iter().map(|l| l.elements).sum();
+        assert_eq!(elements, map.len());
+        assert_eq!(stats.levels.last().unwrap().nodes, stats.leaf_nodes);
+        assert_eq!((stats.boxed_values, stats.boxed_keys), (0, 0));
+    }
+
+    #[test]
+    fn boxed_branches() {
+        let mut map = Map::new();
+        let max = (B * B) as u32;
+        map.extend_sorted_iter((0..max).map(|i| (i, i)));
+        map.flush();
+        let stats = map.stats();
+        let branches: usize = stats.levels[..stats.height - 1]
+            .iter()
+            .map(|l| l.elements)
+            .sum();
+        assert!(branches > 0);
+        assert_eq!((stats.boxed_values, stats.boxed_keys), (0, 0));
+
+        for i in 0..max {
+            map.get(&i);
+        }
+        let stats = map.stats();
+        assert_eq!((stats.boxed_values, stats.boxed_keys), (branches, 0));
+
+        for i in 0..max {
+            map.get_key_value(&i);
+        }
+        assert_eq!(map.stats().boxed_keys, branches);
+    }
+}
@@ -0,0 +1,343 @@
+use std::{
+    convert::Infallible,
+    mem::{replace, take},
+    sync::{
+        Mutex, RwLock,
+        atomic::{self, AtomicUsize},
+    },
+};
+
+use crate::{
+    B,
+    buffer::Buffer,
+    flat::{self, children_toward, extend_buffer, push_down, search, settle_children, settle_leaf},
+    unwind,
+};
+
+/// A map that can be shared between threads.
+///
+/// Like [`Map`](crate::Map), items are buffered when inserted and pushed down
+/// by the queries that pass through them. Every node has its own lock, and
+/// queries hold at most a parent and a child lock at a time, so queries on
+/// different paths push down their buffers in parallel.
+///
+/// Splitting a leaf changes its ancestors, so a query that finds its leaf
+/// overflowing retries with the whole tree locked exclusively.
+///
+/// A panic from the keys' `Ord` or from the closure given to
+/// [`get_with`](SyncMap::get_with) is held back until the locks are
+/// released, so it neither poisons them nor loses buffered items.
+pub struct SyncMap<K, V> {
+    /// Items inserted since the last query, in insertion order. Kept apart
+    /// from the root so that inserts never wait for a query to finish.
+    pending: Mutex<Vec<(K, V)>>,
+    root: RwLock<SyncNode<K, V>>,
+    length: AtomicUsize,
+}
+
+struct SyncNode<K, V> {
+    state: Mutex<NodeState<K, V>>,
+    /// Laid out like [`FlatNode::children`](flat::FlatNode), but kept out of
+    /// the lock, since only splits change them.
+    children: Vec<SyncNode<K, V>>,
+}
+
+struct NodeState<K, V> {
+    buffer: Buffer<K, V>,
+    elements: Vec<(K, V)>,
+}
+
+/// The separator and new right sibling produced by splitting a node.
+type Split<K, V> = ((K, V), SyncNode<K, V>);
+
+impl<K, V> Default for SyncMap<K, V> {
+    fn default() -> Self {
+        SyncMap {
+            pending: Mutex::new(Vec::new()),
+            root: RwLock::new(SyncNode::new(Vec::new(), Vec::new())),
+            length: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<K, V> SyncMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of items inserted, counted like [`Map::len`](crate::Map::len).
+    pub fn len(&self) -> usize {
+        self.length.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.pending.lock().unwrap().push((key, value));
+        self.length.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+}
+
+impl<K: Ord, V> SyncMap<K, V> {
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.get_with(key, V::clone)
+    }
+
+    /// Looks up `key`, and calls `f` on its value while the node holding it
+    /// is still locked.
+    pub fn get_with<R>(&self, key: &K, f: impl FnOnce(&V) -> R) -> Option<R> {
+        let found = self.root.read().unwrap().find(&self.pending, key, f);
+        let result = match found {
+            Ok(result) => result,
+            Err(f) => {
+                let mut root = self.root.write().unwrap();
+                self.settle(&mut root, Some(key));
+                root.find_settled(key, f)
+            }
+        };
+        unwind::resume();
+        result
+    }
+
+    /// Pushes every buffered item down to its leaf.
+    pub fn flush(&self) {
+        self.settle(&mut self.root.write().unwrap(), None);
+        unwind::resume();
+    }
+
+    /// Settles the path to `key`, or the whole tree if `key` is `None`,
+    /// splitting nodes and growing the root as needed.
+    fn settle(&self, root: &mut SyncNode<K, V>, key: Option<&K>) {
+        let pending = take(&mut *self.pending.lock().unwrap());
+        extend_buffer(&mut root.state.get_mut().unwrap().buffer, pending);
+
+        let mut splits = root.settle(key);
+        while !splits.is_empty() {
+            let old_root = replace(root, SyncNode::new(Vec::new(), Vec::new()));
+            let (elements, children): (Vec<_>, Vec<_>) = splits.into_iter().unzip();
+            *root = SyncNode::new(elements, children);
+            root.children.insert(0, old_root);
+            splits = root.split();
+        }
+    }
+}
+
+impl<K, V> SyncNode<K, V> {
+    fn new(elements: Vec<(K, V)>, children: Vec<SyncNode<K, V>>) -> Self {
+        SyncNode {
+            state: Mutex::new(NodeState {
+                buffer: Buffer::default(),
+                elements,
+            }),
+            children,
+        }
+    }
+
+    fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+}
+
+impl<K: Ord, V> SyncNode<K, V> {
+    /// Looks up `key` with lock coupling, pushing down buffers on the way.
+    ///
+    /// Gives `f` back if the leaf would overflow, since splitting it
+    /// needs the tree to be locked exclusively. After a panic, which is
+    /// held back, finds nothing.
+    fn find<R, F: FnOnce(&V) -> R>(
+        &self,
+        pending: &Mutex<Vec<(K, V)>>,
+        key: &K,
+        f: F,
+    ) -> Result<Option<R>, F> {
+        let mut node = self;
+        let mut state = node.state.lock().unwrap();
+        // Taken while the root is locked, so that a query that finds the
+        // root buffer empty can't overtake one that is still filling it.
+        let items = take(&mut *pending.lock().unwrap());
+        extend_buffer(&mut state.buffer, items);
+
+        loop {
+            if node.is_leaf() {
+                if state.elements.len() + state.buffer.len() > B {
+                    return Err(f);
+                }
+                let NodeState { buffer, elements } = &mut *state;
+                settle_leaf(buffer, elements);
+                return Ok(match unwind::catch(|| search(&state.elements, key)) {
+                    Some(Ok(i)) => unwind::catch(|| f(&state.elements[i].1)),
+                    _ => None,
+                });
+            }
+
+            let NodeState { buffer, elements } = &mut *state;
+            push_down(buffer, elements, |i, slice| {
+                flat::append(&mut node.children[i].state.lock().unwrap().buffer, slice);
+            });
+            match unwind::catch(|| search(&state.elements, key)) {
+                None => return Ok(None),
+                Some(Ok(i)) => return Ok(unwind::catch(|| f(&state.elements[i].1))),
+                Some(Err(i)) => {
+                    node = &node.children[i];
+                    // The child is locked before the parent is released.
+                    state = node.state.lock().unwrap();
+                }
+            }
+        }
+    }
+
+    /// Looks up `key` along a path that has already been settled, unless
+    /// a panic is held back, in which case it finds nothing.
+    fn find_settled<R>(&mut self, key: &K, f: impl FnOnce(&V) -> R) -> Option<R> {
+        let mut node = self;
+        loop {
+            let state = node.state.get_mut().unwrap();
+            debug_assert!(state.buffer.is_empty() || unwind::is_held());
+            match unwind::catch(|| search(&state.elements, key))? {
+                Ok(i) => return unwind::catch(|| f(&state.elements[i].1)),
+                Err(_) if node.is_leaf() => return None,
+                Err(i) => node = &mut node.children[i],
+            }
+        }
+    }
+
+    /// Empties the buffers on the path to `key`, or in the whole subtree if
+    /// `key` is `None`. Returns the splits that the parent has to take in.
+    fn settle(&mut self, key: Option<&K>) -> Vec<Split<K, V>> {
+        let NodeState { buffer, elements } = self.state.get_mut().unwrap();
+        if self.children.is_empty() {
+            settle_leaf(buffer, elements);
+            return self.split();
+        }
+
+        let children = &mut self.children;
+        push_down(buffer, elements, |i, slice| {
+            flat::append(&mut children[i].state.get_mut().unwrap().buffer, slice);
+        });
+        let indices = children_toward(elements, key);
+        let Ok(()) = settle_children(elements, children, indices, |child| {
+            Ok::<_, Infallible>(child.settle(key))
+        });
+        self.split()
+    }
+
+    fn split(&mut self) -> Vec<Split<K, V>> {
+        let state = self.state.get_mut().unwrap();
+        flat::split(&mut state.elements, &mut self.children)
+            .into_iter()
+            .map(|(separator, elements, children)| (separator, SyncNode::new(elements, children)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread};
+
+    use super::SyncMap;
+    use crate::{B, flat::tests::check_panicking_ord};
+
+    fn assert_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn is_sync() {
+        assert_sync::<SyncMap<usize, usize>>();
+    }
+
+    #[test]
+    fn insert_get() {
+        let map = SyncMap::new();
+        let max = B * B * 3;
+        for i in 0..max {
+            map.insert(i * 2, i);
+        }
+        for i in 0..max {
+            assert_eq!(map.get(&(i * 2)), Some(i));
+            assert_eq!(map.get(&(i * 2 + 1)), None);
+        }
+        for i in 0..max {
+            map.insert(i * 2, i + 1);
+            map.insert(i * 2 + 1, i);
+        }
+        map.flush();
+        for i in 0..max {
+            assert_eq!(map.get(&(i * 2)), Some(i + 1));
+            assert_eq!(map.get(&(i * 2 + 1)), Some(i));
+        }
+    }
+
+    #[test]
+    fn get_with() {
+        let map = SyncMap::new();
+        map.insert(1, String::from("one"));
+        assert_eq!(map.get_with(&1, |s| s.len()), Some(3));
+        assert_eq!(map.get_with(&2, |s| s.len()), None);
+    }
+
+    #[test]
+    fn concurrent_gets() {
+        let map = SyncMap::new();
+        let max = B * B * 3;
+        for i in (0..max).rev() {
+            map.insert(i, i);
+        }
+
+        thread::scope(|s| {
+            for t in 0..4 {
+                let map = &map;
+                s.spawn(move || {
+                    for i in (t..max).step_by(4) {
+                        assert_eq!(map.get(&i), Some(i));
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn concurrent_inserts_and_gets() {
+        let map = Arc::new(SyncMap::new());
+        let max = B * B;
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let map = map.clone();
+                thread::spawn(move || {
+                    for i in (t..max).step_by(4) {
+                        map.insert(i, i);
+                        assert_eq!(map.get(&i), Some(i));
+                        if i >= 4 {
+                            assert_eq!(map.get(&(i - 4)), Some(i - 4));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(map.len(), max);
+        for i in 0..max {
+            assert_eq!(map.get(&i), Some(i));
+        }
+    }
+
+    #[test]
+    fn panicking_ord() {
+        check_panicking_ord(
+            &mut SyncMap::new(),
+            |map, key, value| map.insert(key, value),
+            |map, key| match key {
+                Some(key) => drop(map.get(key)),
+                None => map.flush(),
+            },
+            |map, key| map.get(key),
+            SyncMap::len,
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    cell::{Cell, RefCell},
+    cmp::Ordering,
+    fmt::{self, Debug},
+    hash::{Hash, Hasher},
+};
+
+use crate::{Array, Branch, InternalArray, LeafArray, Map, MaybeBox, Node, flush::FlushState};
+
+/// Copies the tree as it is, buffers included, without flushing it.
+///
+/// The clone doesn't share the original's write-ahead log or observer,
+/// and its branch values and keys start out unboxed.
+impl<K: Clone, V: Clone> Clone for Map<K, V> {
+    fn clone(&self) -> Self {
+        let flush_state = match *self.flush_state.borrow() {
+            FlushState::Flushed => FlushState::Flushed,
+            // The clone starts any incremental flush over.
+            FlushState::Dirty | FlushState::InProgress(_) => FlushState::Dirty,
+        };
+        Map {
+            root: RefCell::new(self.root.borrow().clone()),
+            length: self.length,
+            flush_state: RefCell::new(flush_state),
+            insert_strategy: self.insert_strategy,
+            read_bias: self.read_bias.clone(),
+            boxed: Cell::new(false),
+            wal: None,
+            observer: RefCell::new(None),
+            #[cfg(feature = "metrics")]
+            metrics: Default::default(),
+        }
+    }
+}
+
+impl<K: Clone, V: Clone> Clone for Node<K, V> {
+    fn clone(&self) -> Self {
+        let array = match &self.array {
+            Array::Internal(internal) => Array::Internal(InternalArray::new(
+                internal.first_child.clone(),
+                Box::new(
+                    internal
+                        .elements
+                        .borrow()
+                        .iter()
+                        .map(Branch::clone)
+                        .collect(),
+                ),
+            )),
+            Array::Leaf(leaf) => Array::Leaf(LeafArray {
+                elements: leaf.elements.clone(),
+            }),
+        };
+        Node {
+            buffer: self.buffer.clone(),
+            array,
+        }
+    }
+}
+
+impl<K: Clone, V: Clone> Clone for Branch<K, V> {
+    fn clone(&self) -> Self {
+        Branch {
+            key: MaybeBox::Inline((*self.key).clone()),
+            value: MaybeBox::Inline((*self.value).clone()),
+            child: self.child.clone(),
+        }
+    }
+}
+
+impl<K, V> Map<K, V> {
+    /// The items of a flushed map, in key order.
+    ///
+    /// # Safety
+    ///
+    /// The map must not be queried or changed while the items are in use.
+    unsafe fn settled_items(&self) -> Vec<(&K, &V)> {
+        let mut items = Vec::new();
+        // The tree isn't borrowed mutably again until the items are dropped,
+        // so they can outlive the borrows that they came from.
+        unsafe { self.root.try_borrow_unguarded() }
+            .expect("map is already being changed")
+            .collect_settled(&mut items);
+        items
+    }
+}
+
+impl<K: Ord, V> Map<K, V> {
+    /// Flushes the map, and runs `f` on its items in key order.
+    fn with_items<R>(&self, f: impl FnOnce(&[(&K, &V)]) -> R) -> R {
+        self.flush();
+        // Only `f` runs while the items are in use, and it has no access to
+        // the map.
+        f(&unsafe { self.settled_items() })
+    }
+
+    /// Like [`with_items`](Map::with_items), for two maps at once. Both are
+    /// flushed before either's items are collected, since they may be the
+    /// same map.
+    fn with_items_of<R>(&self, other: &Self, f: impl FnOnce(&[(&K, &V)], &[(&K, &V)]) -> R) -> R {
+        self.flush();
+        other.flush();
+        f(&unsafe { self.settled_items() }, &unsafe {
+            other.settled_items()
+        })
+    }
+}
+
+impl<K, V> Node<K, V> {
+    fn collect_settled<'a>(&'a self, items: &mut Vec<(&'a K, &'a V)>) {
+        debug_assert!(self.buffer.borrow().is_empty());
+        match &self.array {
+            Array::Internal(internal) => {
+                internal.first_child.collect_settled(items);
+                let elements = unsafe { internal.elements.try_borrow_unguarded() }
+                    .expect("node is already being changed");
+                for branch in elements.iter() {
+                    items.push((&branch.key, &branch.value));
+                    branch.child.collect_settled(items);
+                }
+            }
+            Array::Leaf(leaf) => {
+                let elements = unsafe { leaf.elements.try_borrow_unguarded() }
+                    .expect("node is already being changed");
+                items.extend(elements.iter().map(|(key, value)| (key, value)));
+            }
+        }
+    }
+}
+
+/// Shows the items in key order, flushing the map first. See
+/// [`Map::dump`] for the tree's structure.
+impl<K: Ord + Debug, V: Debug> Debug for Map<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.with_items(|items| f.debug_map().entries(items.iter().copied()).finish())
+    }
+}
+
+/// Compares the items of the maps, flushing them first, so maps with the
+/// same items are equal however they are buffered.
+impl<K: Ord, V: PartialEq> PartialEq for Map<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.with_items_of(other, |items, other_items| items == other_items)
+    }
+}
+
+impl<K: Ord, V: Eq> Eq for Map<K, V> {}
+
+/// Compares the items of the maps in key order, like
+/// [`BTreeMap`](std::collections::BTreeMap) does.
+impl<K: Ord, V: PartialOrd> PartialOrd for Map<K, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.with_items_of(other, |items, other_items| items.partial_cmp(other_items))
+    }
+}
+
+impl<K: Ord, V: Ord> Ord for Map<K, V> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.with_items_of(other, |items, other_items| items.cmp(other_items))
+    }
+}
+
+/// Hashes the items of the map, flushing it first.
+impl<K: Ord + Hash, V: Hash> Hash for Map<K, V> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.with_items(|items| {
+            state.write_usize(items.len());
+            for (key, value) in items {
+                key.hash(state);
+                value.hash(state);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::{BuildHasher, RandomState};
+
+    use crate::{B, Map};
+
+    fn buffered() -> Map<usize, usize> {
+        let mut map = Map::new();
+        for i in (0..B * B).rev() {
+            map.insert(i, 0);
+            map.insert(i, i);
+        }
+        map
+    }
+
+    fn flushed() -> Map<usize, usize> {
+        let mut map = Map::new();
+        map.extend_sorted_iter((0..B * B).map(|i| (i, i)));
+        map
+    }
+
+    #[test]
+    fn equal_however_buffered() {
+        let hasher = RandomState::new();
+        let (a, b) = (buffered(), flushed());
+        assert_eq!(a, b);
+        assert_eq!(hasher.hash_one(&a), hasher.hash_one(&b));
+        assert_eq!(a.cmp(&b), std::cmp::Ordering::Equal);
+        assert_eq!(a, a);
+
+        let mut c = flushed();
+        c.insert(7, 8);
+        assert_ne!(a, c);
+        assert!(a < c);
+        c.insert(7, 7);
+        assert_eq!(a, c);
+        c.insert(B * B, 0);
+        assert!(a < c);
+    }
+
+    #[test]
+    fn clone_keeps_buffers() {
+        let mut map = flushed();
+        for i in (0..B * B).step_by(2) {
+            map.insert(i, i + 1);
+        }
+        for i in B * B - B * 2..B * B {
+            map.get(&i);
+        }
+        assert!(map.stats().boxed_values > 0);
+
+        let clone = map.clone();
+        assert!(clone.stats().buffered > 0);
+        assert_eq!(clone.stats().buffered, map.stats().buffered);
+        assert_eq!(clone.stats().boxed_values, 0);
+        assert_eq!(clone, map);
+        assert_eq!(clone.get(&7), Some(&7));
+        assert_eq!(clone.get(&8), Some(&9));
+    }
+
+    #[test]
+    fn debug() {
+        let mut map = Map::new();
+        map.insert(2, "b");
+        map.insert(1, "a");
+        map.insert(2, "c");
+        assert_eq!(format!("{map:?}"), r#"{1: "a", 2: "c"}"#);
+    }
+}
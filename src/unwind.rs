@@ -0,0 +1,123 @@
+//! Holds panics from user code back until the tree is consistent again.
+//!
+//! Keys' `Ord` and `Drop`, and observers, run in the middle of tree
+//! surgery. Instead of letting a panic unwind through nodes whose items
+//! are being moved around, the code that calls them catches the panic and
+//! stops calling user code. The traversal then finishes its structural
+//! work, and the map rethrows the panic with [`resume`].
+
+use std::{
+    any::Any,
+    cell::RefCell,
+    panic::{self, AssertUnwindSafe},
+};
+
+use crate::{Branch, Motion, Visitor};
+
+thread_local! {
+    /// The first panic caught on this thread and not yet rethrown.
+    static CAUGHT: RefCell<Option<Box<dyn Any + Send>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f`, which may call user code. If it panics, the panic is held
+/// back and `None` is returned. Once a panic is held, `f` isn't run at all.
+pub(crate) fn catch<T>(f: impl FnOnce() -> T) -> Option<T> {
+    if is_held() {
+        return None;
+    }
+    // Callers leave their state valid whether or not `f` finishes.
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => Some(result),
+        Err(payload) => {
+            hold(payload);
+            None
+        }
+    }
+}
+
+/// Drops `value`, holding back a panic from its `Drop` like [`catch`],
+/// but even when a panic is already held.
+pub(crate) fn drop_caught<T>(value: T) {
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| drop(value))) {
+        hold(payload);
+    }
+}
+
+pub(crate) fn is_held() -> bool {
+    CAUGHT.with(|caught| caught.borrow().is_some())
+}
+
+/// Holds back a panic caught elsewhere, such as on another thread. Only
+/// the first panic is kept.
+pub(crate) fn hold(payload: Box<dyn Any + Send>) {
+    CAUGHT.with(|caught| {
+        caught.borrow_mut().get_or_insert(payload);
+    });
+}
+
+/// Takes the panic held back on this thread, if any.
+pub(crate) fn take() -> Option<Box<dyn Any + Send>> {
+    CAUGHT.with(|caught| caught.borrow_mut().take())
+}
+
+/// Rethrows the panic held back on this thread, if any.
+pub(crate) fn resume() {
+    if let Some(payload) = take() {
+        panic::resume_unwind(payload);
+    }
+}
+
+/// Runs each call into a visitor under [`catch`]. Once a panic is held,
+/// the visitor is treated as done.
+pub(crate) struct Guarded<'a, T>(pub(crate) &'a mut T);
+
+impl<K, V, T: Visitor<K, V>> Visitor<K, V> for Guarded<'_, T> {
+    #[inline]
+    fn visit_internal(&mut self, array: &mut [Branch<K, V>], temporary: bool) -> Motion {
+        catch(|| self.0.visit_internal(array, temporary)).unwrap_or(Motion::Finish)
+    }
+
+    #[inline]
+    fn visit_leaf(&mut self, array: &mut [(K, V)]) {
+        catch(|| self.0.visit_leaf(array));
+    }
+
+    #[inline]
+    fn visit_buffer(&mut self, len: usize) {
+        catch(|| self.0.visit_buffer(len));
+    }
+
+    #[inline]
+    fn exhausted(&self) -> bool {
+        catch(|| self.0.exhausted()).unwrap_or(true)
+    }
+
+    #[inline]
+    fn boxed(&self) -> bool {
+        self.0.boxed()
+    }
+
+    #[inline]
+    fn deliver(&mut self) -> Option<(K, V)> {
+        catch(|| self.0.deliver()).flatten()
+    }
+
+    #[inline]
+    fn superseded(&mut self, key: K, value: V) {
+        if is_held() {
+            drop_caught((key, value));
+        } else {
+            catch(|| self.0.superseded(key, value));
+        }
+    }
+
+    #[inline]
+    fn split(&mut self, new_nodes: usize) {
+        catch(|| self.0.split(new_nodes));
+    }
+
+    #[inline]
+    fn pushed_down(&mut self, len: usize) {
+        catch(|| self.0.pushed_down(len));
+    }
+}
@@ -0,0 +1,407 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    Map,
+    codec::{Codec, Decoder, Encoder, ReadError},
+};
+
+/// Appends every item added to a [`Map`] to a log file, before the item
+/// is buffered. The records of each insert or extend reach the operating
+/// system before it returns.
+///
+/// Each record holds its length, then its sequence number, the key and the
+/// value through their [`Codec`]s, then a CRC-32 checksum of all three.
+/// Sequence numbers count every item ever logged, and the snapshot taken
+/// by a checkpoint starts with the sequence number of the first item it
+/// doesn't hold.
+pub(crate) struct Wal<K, V> {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// Set when the WAL is created, which is the only place where the keys
+    /// and values are known to implement [`Codec`].
+    encode: fn(u64, &K, &V, &mut Vec<u8>) -> io::Result<()>,
+    /// The sequence number of the next record.
+    sequence: u64,
+    scratch: Vec<u8>,
+    /// The first error hit while appending, reported by the next sync.
+    error: Option<io::Error>,
+}
+
+impl<K, V> Wal<K, V> {
+    fn append(&mut self, key: &K, value: &V) {
+        let sequence = self.sequence;
+        self.sequence += 1;
+        if self.error.is_some() {
+            return;
+        }
+        self.scratch.clear();
+        let result = (self.encode)(sequence, key, value, &mut self.scratch).and_then(|()| {
+            self.writer
+                .write_all(&(self.scratch.len() as u32).to_le_bytes())?;
+            self.writer.write_all(&self.scratch)
+        });
+        self.error = result.err();
+    }
+
+    /// Hands the records appended so far to the operating system, which
+    /// keeps them if the process crashes.
+    fn flush(&mut self) {
+        if self.error.is_none() {
+            self.error = self.writer.flush().err();
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+}
+
+fn encode_record<K: Codec, V: Codec>(
+    sequence: u64,
+    key: &K,
+    value: &V,
+    out: &mut Vec<u8>,
+) -> io::Result<()> {
+    let mut encoder = Encoder::new(out);
+    encoder.u64(sequence)?;
+    encoder.encode(key)?;
+    encoder.encode(value)?;
+    encoder.finish()
+}
+
+/// Where [`Map::checkpoint`] puts the snapshot for the log at `path`.
+fn snapshot_path(path: &Path) -> PathBuf {
+    path.with_extension("snapshot")
+}
+
+impl<K, V> Map<K, V> {
+    /// Logs an item, if the map has a write-ahead log.
+    pub(crate) fn log(&mut self, key: &K, value: &V) {
+        if let Some(wal) = &mut self.wal {
+            wal.append(key, value);
+        }
+    }
+
+    /// Hands the items logged so far to the operating system, if the map
+    /// has a write-ahead log. Every insert and extend ends with this.
+    pub(crate) fn flush_log(&mut self) {
+        if let Some(wal) = &mut self.wal {
+            wal.flush();
+        }
+    }
+
+    /// Makes sure that everything logged so far is on disk.
+    ///
+    /// Each insert hands its log records to the operating system before it
+    /// returns, so they survive the process crashing without this. Surviving
+    /// a crash of the whole system, or a power loss, takes this call.
+    ///
+    /// Only inserted items are logged. Changes made through
+    /// [`get_mut`](Map::get_mut) and
+    /// [`get_key_value_mut`](Map::get_key_value_mut) are not, and only
+    /// survive a crash once a [`checkpoint`](Map::checkpoint) has written
+    /// them to the snapshot.
+    ///
+    /// Inserts don't fail when appending to the log fails. Instead, the first
+    /// such error is returned here, and nothing more is logged until the
+    /// next successful [`checkpoint`](Map::checkpoint).
+    ///
+    /// Does nothing if the map has no write-ahead log.
+    pub fn sync_wal(&mut self) -> io::Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal.sync(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<K: Ord + Codec, V: Codec> Map<K, V> {
+    /// Restores a map from the write-ahead log at `path`, and keeps logging
+    /// to it.
+    ///
+    /// The map starts out as the snapshot taken by the last
+    /// [`checkpoint`](Map::checkpoint), which is kept next to the log with
+    /// its extension replaced by `snapshot`. The items in the log are then
+    /// added to the root's buffer, in order, skipping those that the
+    /// snapshot already holds. If neither file exists, the map starts out
+    /// empty.
+    ///
+    /// The log is read up to its first incomplete or damaged record, which
+    /// is where a crash interrupted it, and cut off there.
+    pub fn recover(path: impl AsRef<Path>) -> Result<Self, ReadError> {
+        let path = path.as_ref();
+        let (mut map, covered) = match File::open(snapshot_path(path)) {
+            Ok(file) => {
+                let mut reader = BufReader::new(file);
+                let mut covered = [0; 8];
+                reader.read_exact(&mut covered).map_err(ReadError::Io)?;
+                (Map::read_from(reader)?, u64::from_le_bytes(covered))
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => (Map::new(), 0),
+            Err(error) => return Err(ReadError::Io(error)),
+        };
+
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(ReadError::Io)?;
+        let (valid_len, sequence) =
+            replay(&mut map, covered, BufReader::new(&file)).map_err(ReadError::Io)?;
+        file.set_len(valid_len).map_err(ReadError::Io)?;
+
+        map.wal = Some(Wal {
+            path: path.to_owned(),
+            writer: BufWriter::new(file),
+            encode: encode_record::<K, V>,
+            sequence,
+            scratch: Vec::new(),
+            error: None,
+        });
+        Ok(map)
+    }
+
+    /// Durably writes a snapshot of the map, and then empties its
+    /// write-ahead log.
+    ///
+    /// The snapshot keeps the buffers as they are, like
+    /// [`write_structure_to`](Map::write_structure_to). It is written to a
+    /// temporary file first, so a crash never leaves a partial snapshot.
+    /// A crash before the log is emptied leaves records in it that the
+    /// snapshot already holds, which [`recover`](Map::recover) skips.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the map has no write-ahead log, which is only the case
+    /// for maps that weren't created by [`recover`](Map::recover).
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        let Some(wal) = &self.wal else {
+            return Err(io::Error::other("map has no write-ahead log"));
+        };
+        let snapshot = snapshot_path(&wal.path);
+        let temporary = wal.path.with_extension("snapshot.tmp");
+
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        writer.write_all(&wal.sequence.to_le_bytes())?;
+        self.write_structure_to(&mut writer)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temporary, &snapshot)?;
+        #[cfg(unix)]
+        if let Some(dir) = snapshot.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+
+        // Everything logged so far is in the snapshot, including items
+        // whose logging failed.
+        let wal = self.wal.as_mut().unwrap();
+        wal.error = None;
+        wal.writer.flush()?;
+        let file = wal.writer.get_ref();
+        file.set_len(0)?;
+        file.sync_data()
+    }
+}
+
+/// Adds the items of a log to the map, except for those numbered below
+/// `covered`. Returns the length of the part of the log that holds
+/// complete records, and the sequence number to continue logging with.
+fn replay<K: Ord + Codec, V: Codec>(
+    map: &mut Map<K, V>,
+    covered: u64,
+    mut log: impl Read,
+) -> io::Result<(u64, u64)> {
+    let mut valid_len = 0;
+    let mut next = covered;
+    let mut record = Vec::new();
+    loop {
+        let mut len = [0; 4];
+        match log.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok((valid_len, next));
+            }
+            Err(error) => return Err(error),
+        }
+        let len = u32::from_le_bytes(len);
+        record.clear();
+        (&mut log).take(len.into()).read_to_end(&mut record)?;
+        if record.len() != len as usize {
+            return Ok((valid_len, next));
+        }
+
+        let mut decoder = Decoder::new(&record[..]);
+        let item = (|| {
+            let item = (decoder.u64()?, decoder.decode()?, decoder.decode()?);
+            decoder.finish()?;
+            Ok::<_, ReadError>(item)
+        })();
+        let Ok((sequence, key, value)) = item else {
+            return Ok((valid_len, next));
+        };
+        if sequence >= covered {
+            map.insert(key, value);
+        }
+        next = next.max(sequence + 1);
+        valid_len += 4 + u64::from(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write};
+
+    use crate::{B, Map};
+
+    #[test]
+    fn recover_without_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map.wal");
+        {
+            let mut map = Map::<u32, String>::recover(&path).unwrap();
+            for i in 0..B as u32 * 3 {
+                map.insert(i, i.to_string());
+            }
+            map.extend_iter([(1, String::from("one"))]);
+            map.sync_wal().unwrap();
+        }
+
+        let map = Map::<u32, String>::recover(&path).unwrap();
+        assert_eq!(map.len(), B * 3 + 1);
+        assert_eq!(map.get(&1).unwrap(), "one");
+        assert_eq!(map.get(&2).unwrap(), "2");
+    }
+
+    #[test]
+    fn recover_after_process_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map.wal");
+        let mut map = Map::<u32, u32>::recover(&path).unwrap();
+        map.extend_sorted_iter((0..10).map(|i| (i, i)));
+        map.insert(3, 30);
+        map.extend_iter([(5, 50), (20, 20)]);
+        map.extend_from_sorted_vec(&mut vec![(7, 70)]);
+        // Like the process crashing, without a sync or a drop.
+        std::mem::forget(map);
+
+        let map = Map::<u32, u32>::recover(&path).unwrap();
+        assert_eq!(map.len(), 14);
+        assert_eq!(map.get(&3), Some(&30));
+        assert_eq!(map.get(&5), Some(&50));
+        assert_eq!(map.get(&7), Some(&70));
+        assert_eq!(map.get(&9), Some(&9));
+        assert_eq!(map.get(&20), Some(&20));
+    }
+
+    #[test]
+    fn unsorted_bulk_load_logs_what_it_adds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map.wal");
+        let len = {
+            let mut map = Map::<u32, u32>::recover(&path).unwrap();
+            let items = [(1, 1), (2, 2), (2, 3), (4, 4), (3, 3), (5, 5)];
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                map.extend_sorted_iter(items);
+            }));
+            assert!(result.is_err());
+            map.len()
+        };
+
+        let map = Map::<u32, u32>::recover(&path).unwrap();
+        assert_eq!(map.len(), len);
+        assert_eq!(map.get(&2), Some(&3));
+        assert_eq!(map.get(&4), Some(&4));
+        assert_eq!(map.get(&3), None);
+        assert_eq!(map.get(&5), None);
+    }
+
+    #[test]
+    fn checkpoint_truncates_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map.wal");
+        let max = (B * B) as u64;
+        {
+            let mut map = Map::<u64, u64>::recover(&path).unwrap();
+            map.extend_sorted_iter((0..max).map(|i| (i, i)));
+            map.checkpoint().unwrap();
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+            assert!(path.with_extension("snapshot").exists());
+
+            map.insert(0, 1);
+            map.insert(max, max);
+        }
+
+        let map = Map::<u64, u64>::recover(&path).unwrap();
+        assert_eq!(map.get(&0), Some(&1));
+        assert_eq!(map.get(&max), Some(&max));
+        assert_eq!(map.get(&(max - 1)), Some(&(max - 1)));
+    }
+
+    #[test]
+    fn crash_before_truncating_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map.wal");
+        let max = B as u32 * 3;
+        let log = {
+            let mut map = Map::<u32, u32>::recover(&path).unwrap();
+            for i in 0..max {
+                map.insert(i, i);
+            }
+            map.sync_wal().unwrap();
+            let log = std::fs::read(&path).unwrap();
+            map.checkpoint().unwrap();
+            log
+        };
+        // As if the checkpoint crashed right after renaming the snapshot.
+        std::fs::write(&path, log).unwrap();
+
+        let mut map = Map::<u32, u32>::recover(&path).unwrap();
+        assert_eq!(map.len(), max as usize);
+        map.insert(0, 1);
+        drop(map);
+
+        let map = Map::<u32, u32>::recover(&path).unwrap();
+        assert_eq!(map.len(), max as usize + 1);
+        assert_eq!(map.get(&0), Some(&1));
+    }
+
+    #[test]
+    fn torn_record_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map.wal");
+        {
+            let mut map = Map::<u32, u32>::recover(&path).unwrap();
+            map.insert(1, 1);
+            map.insert(2, 2);
+        }
+        let len = std::fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[20, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+
+        let mut map = Map::<u32, u32>::recover(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        map.insert(3, 3);
+        drop(map);
+
+        let map = Map::<u32, u32>::recover(&path).unwrap();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get(&3), Some(&3));
+    }
+
+    #[test]
+    fn checkpoint_needs_wal() {
+        let mut map = Map::<u32, u32>::new();
+        map.insert(1, 1);
+        assert!(map.sync_wal().is_ok());
+        assert!(map.checkpoint().is_err());
+    }
+}